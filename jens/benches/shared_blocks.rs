@@ -0,0 +1,55 @@
+use criterion::{black_box, criterion_group, criterion_main, Criterion};
+use jens::{Block, File};
+
+const TEMPLATES: &str = "type_def =
+    export type ${name} = {
+        ${fields}
+    }
+----
+
+field = ${name}: ${type},
+
+usage =
+    function use_${n}(value: ${type_def}) {
+        return value;
+    }
+----
+";
+
+/// A type definition with `count` fields, standing in for a large block that gets
+/// inserted into many places.
+fn type_def(f: &File, count: usize) -> Block {
+    f.template("type_def").set("name", "Large").set(
+        "fields",
+        Block::join_each(0..count, |i, _| {
+            f.template("field")
+                .set("name", format!("field_{}", i))
+                .set("type", "string")
+        }),
+    )
+}
+
+fn clone_large_block(c: &mut Criterion) {
+    let f = File::parse(TEMPLATES).unwrap();
+    let block = type_def(&f, 1000);
+    c.bench_function("clone a 1000 line block", |b| {
+        b.iter(|| black_box(&block).clone())
+    });
+}
+
+fn insert_large_block(c: &mut Criterion) {
+    let f = File::parse(TEMPLATES).unwrap();
+    let block = type_def(&f, 1000);
+    c.bench_function("insert a 1000 line block 500 times", |b| {
+        b.iter(|| {
+            Block::join_each(0..500, |n, _| {
+                f.template("usage")
+                    .set("n", n.to_string())
+                    .set("type_def", black_box(&block).clone())
+            })
+        })
+    });
+}
+
+criterion_group!(benches, clone_large_block, insert_large_block);
+criterion_main!(benches);
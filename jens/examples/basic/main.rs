@@ -18,7 +18,7 @@ fn main() {
             "map",
             f.template("map").set("name", "EMOJI_MAP").set(
                 "entries",
-                Block::join_map(&emoji, |&(key, val), _| {
+                Block::join_each(&emoji, |&(key, val), _| {
                     f.template("key_value").set("key", key).set("value", val)
                 }),
             ),
@@ -27,7 +27,7 @@ fn main() {
             "logger",
             f.template("logger").set(
                 "functions",
-                Block::join_map(&emoji, |&(key, _), _| {
+                Block::join_each(&emoji, |&(key, _), _| {
                     f.template("log_function")
                         .set("key", key)
                         .set("map", "EMOJI_MAP")
@@ -2,6 +2,7 @@ extern crate jens;
 
 use jens::{Block, File};
 
+#[allow(clippy::enum_variant_names)]
 enum Json {
     JsString,
     JsNumber,
@@ -23,8 +24,8 @@ impl Json {
     fn get_serialize_func(&self) -> String {
         use Json::*;
         match self {
-            JsString => format!("noop"),
-            JsNumber => format!("noop"),
+            JsString => "noop".into(),
+            JsNumber => "noop".into(),
             JsArray(subtype) => format!("serialize_array({})", subtype.get_serialize_func()),
             JsObject(module_name) => format!("{}.serialize", module_name),
         }
@@ -33,8 +34,8 @@ impl Json {
     fn get_deserialize_func(&self) -> String {
         use Json::*;
         match self {
-            JsString => format!("deserialize_string"),
-            JsNumber => format!("deserialize_number"),
+            JsString => "deserialize_string".into(),
+            JsNumber => "deserialize_number".into(),
             JsArray(subtype) => format!("deserialize_array({})", subtype.get_deserialize_func()),
             JsObject(module_name) => format!("{}.deserialize", module_name),
         }
@@ -97,7 +98,7 @@ fn main() {
 
     let output = f.template("main").set(
         "types",
-        Block::join_map(types, |t, _| {
+        Block::join_each(types, |t, _| {
             f.template("type")
                 .set("type_name", t.type_name.clone())
                 .set("type_def_fields", type_def_fields(&f, &t))
@@ -110,7 +111,7 @@ fn main() {
 }
 
 fn type_def_fields(f: &File, t: &TsType) -> Block {
-    Block::join_map(&t.fields, |field, _| {
+    Block::join_each(&t.fields, |field, _| {
         f.template("type_def")
             .set("field_name", field.field_name.clone())
             .set("field_type", field.field_type.get_ts_type())
@@ -118,7 +119,7 @@ fn type_def_fields(f: &File, t: &TsType) -> Block {
 }
 
 fn serialize_fields(f: &File, t: &TsType) -> Block {
-    Block::join_map(&t.fields, |field, _| {
+    Block::join_each(&t.fields, |field, _| {
         f.template("serialize_field")
             .set("field_name", field.field_name.clone())
             .set("serialize_func", field.field_type.get_serialize_func())
@@ -126,7 +127,7 @@ fn serialize_fields(f: &File, t: &TsType) -> Block {
 }
 
 fn deserialize_fields(f: &File, t: &TsType) -> Block {
-    Block::join_map(&t.fields, |field, _| {
+    Block::join_each(&t.fields, |field, _| {
         f.template("deserialize_field")
             .set("field_name", field.field_name.clone())
             .set("deserialize_func", field.field_type.get_deserialize_func())
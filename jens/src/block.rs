@@ -1,5 +1,24 @@
-use crate::parser::{segment::Segment, template::Template};
-use std::fmt;
+use crate::{
+    coverage::Coverage,
+    escape::Escaper,
+    ident::{Case, Symbol},
+    parser::{segment::Segment, template::Template},
+    render::{leading_whitespace, replace_chars_with_whitespace, Renderer},
+    resolver::Resolver,
+};
+use std::{
+    borrow::Cow,
+    fmt,
+    hash::{Hash, Hasher},
+    iter::{self, FromIterator},
+    mem,
+    ops::{Add, AddAssign},
+    slice,
+    sync::{Arc, RwLock},
+};
+
+#[cfg(feature = "serde")]
+mod serialize;
 
 /// When mapping over an iterable, this returns the location of the current iteration
 pub enum IteratorLocation {
@@ -16,168 +35,2117 @@ pub enum IteratorLocation {
     Only,
 }
 
-/// Replace every character in a string with a space, but preserve tabs
-fn replace_chars_with_whitespace(line: &str) -> String {
-    let mut out = String::with_capacity(line.len());
-    for c in line.chars() {
-        match c {
-            '\t' => out.push('\t'),
-            _ => out.push(' '),
+/// The position of an item in an iterable, passed to the mapper by `Block::join_each`
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct Position {
+    /// The index of this item, starting from zero
+    pub index: usize,
+
+    /// The number of items in the iterable, if the iterator knows its exact size
+    /// up front (see `Iterator::size_hint`)
+    pub len: Option<usize>,
+
+    pub is_first: bool,
+    pub is_last: bool,
+}
+
+impl Position {
+    /// Whether this is the only item in the iterable (ie, the first AND last)
+    pub fn is_only(&self) -> bool {
+        self.is_first && self.is_last
+    }
+
+    /// Whether this item is neither the first nor the last
+    pub fn is_middle(&self) -> bool {
+        !self.is_first && !self.is_last
+    }
+
+    /// The number of items after this one, if the length of the iterable is known
+    pub fn remaining(&self) -> Option<usize> {
+        self.len.map(|len| len - self.index - 1)
+    }
+
+    /// The equivalent `IteratorLocation`, as passed by `Block::join_map`
+    pub fn location(&self) -> IteratorLocation {
+        match (self.is_first, self.is_last) {
+            (true, true) => IteratorLocation::Only,
+            (true, false) => IteratorLocation::First,
+            (false, true) => IteratorLocation::Last,
+            (false, false) => IteratorLocation::Nth(self.index),
         }
     }
-    out
 }
 
 /// Represents a segment of a line, potentially containing another block
-#[derive(Clone, PartialEq, Debug)]
+///
+/// Content is stored as a `Cow` so that text known at compile time (such as the
+/// templates generated by `jens_derive`) never needs to be copied.
+#[derive(Clone, PartialEq, Eq, Hash, Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(rename_all = "snake_case"))]
 pub enum LineSegment {
-    Content(String),
-    Placeholder(String),
+    Content(Cow<'static, str>),
+    Placeholder(Placeholder),
     Block(Block),
+
+    /// Writes nothing itself, but the line it is in is only written when the
+    /// condition holds.
+    Condition(Condition),
+
+    /// A list of blocks, written on one line if it fits within the width given to
+    /// the `Renderer`, or otherwise with each block on a line of its own.
+    Group(Box<Group>),
+
+    /// Text which is reflowed to fit within a width when it is rendered.
+    Wrapped(Wrapped),
+
+    /// A fresh identifier, named when it's rendered.
+    Symbol(Symbol),
+
+    /// Written as the block the slot is filled with, which may happen after the
+    /// slot was inserted.
+    Slot(Slot),
+
+    /// Writes nothing, but records where the line it starts came from.
+    Source(Arc<Source>),
+
+    /// Filled when rendered with the items hoisted under a key by the blocks in the
+    /// tree being rendered. See `Block::hoisted`.
+    Hoisted(String),
+
+    /// Marks the start of a column (`${|}` in a template). Across consecutive lines
+    /// of a block, the text following each line's first marker is padded to start
+    /// at the same column, and likewise for the second marker, and so on.
+    Align,
+
+    /// Ends the current line with a newline. At the end of the last line of a
+    /// block, this means the block ends with a newline, and text following the
+    /// block starts on a new line.
+    ///
+    /// A line that has already been ended this way isn't followed by another
+    /// newline, so joining blocks that end with newlines doesn't introduce blank
+    /// lines between them.
     EndOfInput,
 }
 
 impl LineSegment {
-    fn write_to(&self, f: &mut fmt::Formatter, prefix: &str) -> fmt::Result {
-        match self {
-            LineSegment::Content(s) => write!(f, "{}", s),
-            LineSegment::Placeholder(s) => write!(f, "${{{}}}", s),
-            LineSegment::Block(b) => {
-                let prefix = replace_chars_with_whitespace(prefix);
-                b.write_to(f, &prefix)
-            }
-            LineSegment::EndOfInput => Ok(()),
+    fn replace(&mut self, new_segment: LineSegment) {
+        if let LineSegment::Placeholder(_) = self {
+            *self = new_segment;
+        }
+    }
+}
+
+/// A named gap in a line, to be filled with a block
+#[derive(Clone, PartialEq, Eq, Hash, Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Placeholder {
+    pub name: String,
+
+    /// How the block filling this placeholder is indented, overriding the block's
+    /// own indentation mode. Set in a template with `${name:mode}`.
+    #[cfg_attr(
+        feature = "serde",
+        serde(default, skip_serializing_if = "Option::is_none")
+    )]
+    pub indent: Option<Indent>,
+
+    /// The case the text filling this placeholder is converted to. Set in a template
+    /// with `${name:case}`, such as `${name:pascal}`. Only text values are converted
+    /// (see `Block::trusted`), since other blocks can contain placeholders, symbols
+    /// and slots which are only written when the block is rendered.
+    #[cfg_attr(
+        feature = "serde",
+        serde(default, skip_serializing_if = "Option::is_none")
+    )]
+    pub case: Option<Case>,
+}
+
+impl Placeholder {
+    pub fn new<T: Into<String>>(name: T) -> Self {
+        Placeholder {
+            name: name.into(),
+            indent: None,
+            case: None,
         }
     }
 
-    fn replace(&mut self, new_segment: LineSegment) {
-        match self.clone() {
-            LineSegment::Placeholder(_) => {
-                *self = new_segment;
+    pub fn with_indent(mut self, indent: Indent) -> Self {
+        self.indent = Some(indent);
+        self
+    }
+
+    pub fn with_case(mut self, case: Case) -> Self {
+        self.case = Some(case);
+        self
+    }
+
+    /// The block to insert in place of this placeholder, given its content.
+    pub fn fill(&self, content: &Block) -> Block {
+        self.fill_escaped(content, None)
+    }
+
+    /// Like `fill`, with text values then escaped by `escaper`, so that the case is
+    /// converted before escaping.
+    pub fn fill_escaped(&self, content: &Block, escaper: Option<&dyn Escaper>) -> Block {
+        let mut block = match self.case {
+            Some(case) if !content.trusted => content.converted(case),
+            _ => content.clone(),
+        };
+        if let Some(escaper) = escaper {
+            block = block.escaped(escaper);
+        }
+        if let Some(indent) = self.indent {
+            block = block.with_indent(indent);
+        }
+        block
+    }
+}
+
+/// A condition on a line, which holds when the placeholder `name` is set to a block
+/// that renders something. Written in a template as `${?name}`.
+#[derive(Clone, PartialEq, Eq, Hash, Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Condition {
+    pub name: String,
+
+    /// The value the placeholder was set to. If `None`, the placeholder is looked
+    /// up in the `Resolver` when the block is rendered.
+    #[cfg_attr(
+        feature = "serde",
+        serde(default, skip_serializing_if = "Option::is_none")
+    )]
+    pub value: Option<Block>,
+}
+
+impl Condition {
+    pub fn new<T: Into<String>>(name: T) -> Self {
+        Condition {
+            name: name.into(),
+            value: None,
+        }
+    }
+}
+
+/// A handle to a gap which can be inserted into blocks before the block that fills
+/// it is known, and filled later (such as with a count of everything generated).
+///
+/// Every clone of a slot refers to the same gap. Rendering a block with a slot that
+/// hasn't been filled, or that was filled with a block containing the slot itself,
+/// is an error (see `Renderer::try_render`).
+#[derive(Clone)]
+pub struct Slot {
+    name: Arc<str>,
+    value: Arc<RwLock<Option<Block>>>,
+}
+
+impl Slot {
+    /// Create an empty slot. The name is used in the error if it's never filled.
+    pub fn new(name: &str) -> Self {
+        Slot {
+            name: name.into(),
+            value: Arc::default(),
+        }
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Fill the slot with `content`, replacing anything it was filled with before.
+    pub fn fill<T: Into<Block>>(&self, content: T) {
+        *self.value.write().unwrap() = Some(content.into());
+    }
+
+    /// The block this slot has been filled with, if any.
+    pub fn value(&self) -> Option<Block> {
+        self.value.read().unwrap().clone()
+    }
+}
+
+/// Slots are equal when they are clones of each other.
+impl PartialEq for Slot {
+    fn eq(&self, other: &Self) -> bool {
+        Arc::ptr_eq(&self.value, &other.value)
+    }
+}
+
+impl Eq for Slot {}
+
+impl Hash for Slot {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        Arc::as_ptr(&self.value).hash(state);
+    }
+}
+
+impl fmt::Debug for Slot {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        // Not the value, which may contain the slot itself
+        f.debug_struct("Slot")
+            .field("name", &self.name)
+            .field("filled", &self.value.read().unwrap().is_some())
+            .finish()
+    }
+}
+
+impl From<Slot> for Block {
+    fn from(slot: Slot) -> Self {
+        Block::new(vec![Line(vec![LineSegment::Slot(slot)])])
+    }
+}
+
+impl<'a> From<&'a Slot> for Block {
+    fn from(slot: &'a Slot) -> Self {
+        Block::from(slot.clone())
+    }
+}
+
+/// A list of items between delimiters, such as the arguments of a function call,
+/// which is laid out depending on the space available when it is rendered.
+///
+/// If the group fits within the `Renderer`'s width, it's written on one line with a
+/// space after each separator (`f(a, b)`). Otherwise each item goes on a line of its
+/// own, indented by one level (see `Renderer::indent_unit`), with the closing
+/// delimiter on a line of its own:
+///
+/// ```text
+/// f(
+///     a,
+///     b
+/// )
+/// ```
+#[derive(Clone, PartialEq, Eq, Hash, Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Group {
+    pub open: Cow<'static, str>,
+    pub items: Vec<Block>,
+    pub separator: Cow<'static, str>,
+    pub close: Cow<'static, str>,
+
+    /// Whether the last item is followed by a separator when the group is broken
+    /// onto multiple lines.
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub trailing_separator: bool,
+}
+
+impl Group {
+    pub fn new<O, T, S, C>(open: O, items: T, separator: S, close: C) -> Self
+    where
+        O: Into<Cow<'static, str>>,
+        T: IntoIterator<Item = Block>,
+        S: Into<Cow<'static, str>>,
+        C: Into<Cow<'static, str>>,
+    {
+        Group {
+            open: open.into(),
+            items: items.into_iter().collect(),
+            separator: separator.into(),
+            close: close.into(),
+            trailing_separator: false,
+        }
+    }
+
+    pub fn with_trailing_separator(mut self) -> Self {
+        self.trailing_separator = true;
+        self
+    }
+}
+
+impl From<Group> for Block {
+    fn from(group: Group) -> Self {
+        Block::new(vec![Line(vec![LineSegment::Group(Box::new(group))])])
+    }
+}
+
+/// Text to be split into lines of words, each starting with a prefix (such as `/// `),
+/// which fit within `width` columns including the column the text is rendered at.
+/// Paragraphs are separated by blank lines, and otherwise line breaks in the text
+/// are treated like spaces. See `Block::wrapped`.
+#[derive(Clone, PartialEq, Eq, Hash, Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Wrapped {
+    pub text: Cow<'static, str>,
+    pub width: usize,
+    pub prefix: Cow<'static, str>,
+}
+
+impl Wrapped {
+    /// The paragraphs of the text, as lists of words.
+    pub(crate) fn paragraphs(&self) -> Vec<Vec<&str>> {
+        let mut paragraphs = vec![];
+        let mut words = vec![];
+        for line in self.text.lines() {
+            if line.trim().is_empty() {
+                if !words.is_empty() {
+                    paragraphs.push(mem::take(&mut words));
+                }
+                continue;
             }
-            _ => (),
+            words.extend(line.split_whitespace());
         }
+        if !words.is_empty() {
+            paragraphs.push(words);
+        }
+        paragraphs
+    }
+}
+
+impl<'a> From<&'a str> for Placeholder {
+    fn from(name: &'a str) -> Self {
+        Placeholder::new(name)
     }
 }
 
 impl<T: Into<String>> From<T> for LineSegment {
     fn from(v: T) -> Self {
-        LineSegment::Content(v.into())
+        LineSegment::Content(Cow::Owned(v.into()))
+    }
+}
+
+impl From<Placeholder> for LineSegment {
+    fn from(placeholder: Placeholder) -> Self {
+        LineSegment::Placeholder(placeholder)
+    }
+}
+
+impl From<Block> for LineSegment {
+    fn from(block: Block) -> Self {
+        LineSegment::Block(block)
+    }
+}
+
+/// Writes the segment as it would be rendered on a line of its own.
+impl fmt::Display for LineSegment {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        Line(vec![self.clone()]).fmt(f)
+    }
+}
+
+/// The line of a template that a line of a block was created from.
+#[derive(Clone, PartialEq, Eq, Hash, Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Source {
+    pub template: Cow<'static, str>,
+
+    /// The template file, if known (see `File::with_path`).
+    pub file: Option<Cow<'static, str>>,
+
+    /// The line in the template file, starting from 1.
+    pub line: usize,
+}
+
+/// Writes the location as `template (file:line)`.
+impl fmt::Display for Source {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match &self.file {
+            Some(file) => write!(f, "{} ({}:{})", self.template, file, self.line),
+            None => write!(f, "{} (line {})", self.template, self.line),
+        }
     }
 }
 
 /// Represents a single line inside a block of text
-#[derive(Clone, PartialEq, Debug)]
+#[derive(Clone, Debug, Default)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct Line(pub Vec<LineSegment>);
 
+/// Lines are equal when their contents are, regardless of where they came from.
+impl PartialEq for Line {
+    fn eq(&self, other: &Self) -> bool {
+        self.contents().eq(other.contents())
+    }
+}
+
+impl Eq for Line {}
+
+impl Hash for Line {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        for segment in self.contents() {
+            segment.hash(state);
+        }
+    }
+}
+
 impl<T: Into<String>> From<T> for Line {
     fn from(v: T) -> Self {
-        Line(vec![LineSegment::Content(v.into())])
+        Line(vec![LineSegment::from(v)])
     }
 }
 
-impl Line {
-    fn write_to(&self, f: &mut fmt::Formatter, prefix: &str) -> fmt::Result {
-        let mut sub_prefix = String::from(prefix);
-        for segment in &self.0 {
-            match segment {
-                LineSegment::Content(x) => sub_prefix = sub_prefix + x,
-                _ => (),
+impl From<LineSegment> for Line {
+    fn from(segment: LineSegment) -> Self {
+        Line(vec![segment])
+    }
+}
+
+impl From<Block> for Line {
+    fn from(block: Block) -> Self {
+        Line(vec![LineSegment::Block(block)])
+    }
+}
+
+impl FromIterator<LineSegment> for Line {
+    fn from_iter<T: IntoIterator<Item = LineSegment>>(iter: T) -> Self {
+        Line(iter.into_iter().collect())
+    }
+}
+
+impl Extend<LineSegment> for Line {
+    fn extend<T: IntoIterator<Item = LineSegment>>(&mut self, iter: T) {
+        self.0.extend(iter)
+    }
+}
+
+/// Writes the line as it would be rendered on its own.
+impl fmt::Display for Line {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        Block::new(vec![self.clone()]).fmt(f)
+    }
+}
+
+/// The FNV-1a hash, which unlike `DefaultHasher` is the same between runs.
+struct Fnv(u64);
+
+impl Default for Fnv {
+    fn default() -> Self {
+        Fnv(0xcbf2_9ce4_8422_2325)
+    }
+}
+
+impl Hasher for Fnv {
+    fn write(&mut self, bytes: &[u8]) {
+        for &byte in bytes {
+            self.0 = (self.0 ^ u64::from(byte)).wrapping_mul(0x0100_0000_01b3);
+        }
+    }
+
+    fn finish(&self) -> u64 {
+        self.0
+    }
+}
+
+/// A block being normalized by `Block::normalize`, up to the segment `segment` of
+/// its line `line`.
+struct Normalizing<'a> {
+    original: &'a Block,
+
+    /// The normalized block, apart from its lines
+    block: Block,
+
+    /// The normalized lines so far, not including the one being flattened
+    lines: Vec<Line>,
+
+    /// Whether any of `lines` is written whenever the block is, so that the line
+    /// being flattened can't be the first one written
+    written: bool,
+    line: usize,
+    segment: usize,
+    flat: FlatLine,
+
+    /// Whether blocks spanning several lines can be flattened into the lines
+    split: bool,
+
+    /// Whether there are alignment markers in the block, including in the blocks
+    /// kept nested in it. Flattening lines would change which lines they align.
+    aligned: bool,
+
+    /// Whether a block spanning several lines has been flattened into the lines
+    split_lines: bool,
+
+    /// The normalized blocks nested in this one so far, and whether each is aligned
+    nested: Vec<(Block, bool)>,
+}
+
+/// A line of a block being normalized, with the lines of any nested blocks
+/// flattened into it so far.
+#[derive(Default)]
+struct FlatLine {
+    segments: Vec<LineSegment>,
+
+    /// The text of the line so far, if it's known before rendering
+    text: Option<String>,
+
+    /// The source of the line so far, if the line has set it
+    source: Option<Arc<Source>>,
+
+    /// Whether this is the first line of the block
+    first: bool,
+
+    /// Whether a block spanning several lines can be flattened into this one
+    split: bool,
+}
+
+/// A nested block whose lines are being flattened into those of the block it's in,
+/// up to the segment `segment` of its line `line`.
+struct Flattening {
+    block: Block,
+    line: usize,
+    segment: usize,
+
+    /// Added before each of its lines after the first
+    indentation: String,
+
+    /// The source the block was inserted with, which each of its lines after the
+    /// first starts with, rather than the one the line it's flattened into did
+    source: Option<Arc<Source>>,
+}
+
+impl<'a> Normalizing<'a> {
+    fn new(original: &'a Block, split: bool) -> Self {
+        let aligned = original
+            .lines()
+            .any(|line| line.0.iter().any(|s| matches!(s, LineSegment::Align)));
+        let mut normalizing = Normalizing {
+            original,
+            block: original.clone(),
+            lines: Vec::with_capacity(original.lines.len()),
+            written: false,
+            line: 0,
+            segment: 0,
+            flat: FlatLine::default(),
+            split: split && !aligned,
+            aligned,
+            split_lines: false,
+            nested: Vec::new(),
+        };
+        normalizing.start_line();
+        normalizing
+    }
+
+    fn start_line(&mut self) {
+        let line = match self.original.lines.get(self.line) {
+            Some(line) => line,
+            None => return,
+        };
+        let split = self.split
+            && !line
+                .0
+                .iter()
+                .any(|s| matches!(s, LineSegment::Condition(_) | LineSegment::EndOfInput));
+        self.flat = FlatLine {
+            text: Some(String::new()),
+            first: !self.written,
+            split,
+            ..FlatLine::default()
+        };
+    }
+
+    /// Add the segments of the lines up to the next nested block, and return it to
+    /// be normalized and passed to `add_nested`. `None` once all lines are added.
+    fn next_nested(&mut self) -> Option<&'a Block> {
+        let original: &'a Block = self.original;
+        while let Some(line) = original.lines.get(self.line) {
+            while let Some(segment) = line.0.get(self.segment) {
+                self.segment += 1;
+                match segment {
+                    LineSegment::Block(nested) => return Some(nested),
+                    _ => self.flat.push(segment),
+                }
             }
-            segment.write_to(f, &sub_prefix)?;
+            self.end_line();
+            self.line += 1;
+            self.segment = 0;
+            self.start_line();
         }
-        Ok(())
+        None
     }
 
-    pub fn set(&mut self, placeholder_name: &str, content: &Block) {
-        for segment in &mut self.0 {
-            match segment.clone() {
-                LineSegment::Placeholder(ref name) if name == placeholder_name => {
-                    segment.replace(LineSegment::Block(content.clone()));
+    /// Add the normalized form of a nested block to the line, flattening its lines
+    /// into those of this block if that doesn't change how it renders (which for
+    /// a block spanning several lines is only allowed if the line is `split`).
+    ///
+    /// The blocks left nested in a flattened block are now part of this line, where
+    /// they may be flattened too, so they are added in the same way.
+    fn add_nested(&mut self, nested: Block, aligned: bool) {
+        self.nested.push((nested.clone(), aligned));
+        if aligned {
+            self.aligned = true;
+            self.split = false;
+            self.flat.split = false;
+        }
+        // The flattened blocks whose lines are being added, innermost last
+        let mut flattening: Vec<Flattening> = Vec::new();
+        let mut next = Some(nested);
+        loop {
+            let flat = &mut self.flat;
+            if let Some(nested) = next.take() {
+                match self
+                    .block
+                    .flattened_indentation(&nested, flat.split, &flat.text, flat.first)
+                {
+                    Some(indentation) => {
+                        self.split_lines |= nested.lines.len() > 1;
+                        if !nested.hoisted_items().is_empty() {
+                            let hoisted = nested.hoisted_items().iter().cloned();
+                            self.block.extra_mut().hoisted.extend(hoisted);
+                        }
+                        flattening.push(Flattening {
+                            block: nested,
+                            line: 0,
+                            segment: 0,
+                            indentation,
+                            source: flat.source.clone(),
+                        });
+                    }
+                    None => {
+                        flat.text = None;
+                        flat.segments.push(LineSegment::Block(nested));
+                    }
                 }
-                _ => (),
             }
+            let nested = match flattening.last_mut() {
+                Some(nested) => nested,
+                None => return,
+            };
+            let nested_line = &nested.block.lines[nested.line];
+            if let Some(nested_segment) = nested_line.0.get(nested.segment) {
+                nested.segment += 1;
+                match nested_segment {
+                    LineSegment::Block(block) => next = Some(block.clone()),
+                    _ => flat.push(nested_segment),
+                }
+                continue;
+            }
+            nested.line += 1;
+            nested.segment = 0;
+            let nested_line = match nested.block.lines.get(nested.line) {
+                Some(nested_line) => nested_line,
+                None => {
+                    flattening.pop();
+                    continue;
+                }
+            };
+            self.end_line();
+            let flat = &mut self.flat;
+            flat.first = !self.written;
+            flat.text = Some(String::new());
+            flat.source = nested.source.clone();
+            if nested_line.source().is_none() {
+                let source = nested.source.clone().map(LineSegment::Source);
+                flat.segments.extend(source);
+            }
+            if !nested_line.is_blank() {
+                flat.segments
+                    .push(LineSegment::from(nested.indentation.clone()));
+                flat.text = Some(nested.indentation.clone());
+            }
+        }
+    }
+
+    fn end_line(&mut self) {
+        let line = Line::merge_text(mem::take(&mut self.flat.segments));
+        self.written |= line.is_always_written();
+        self.lines.push(line);
+    }
+
+    /// The normalized block, and whether it's aligned.
+    fn finish(mut self) -> (Block, bool) {
+        if self.aligned && self.split_lines {
+            // Alignment markers were only found after lines had been flattened, so
+            // start again without flattening any
+            let mut normalizing = Normalizing::new(self.original, false);
+            for (nested, aligned) in mem::take(&mut self.nested) {
+                normalizing.next_nested();
+                normalizing.add_nested(nested, aligned);
+            }
+            normalizing.next_nested();
+            return normalizing.finish();
         }
+        self.block.lines = Arc::new(self.lines);
+        (self.block, self.aligned)
     }
 }
 
-/// A `Block` is one or many lines of text. More blocks can be embedded within a
-/// line, in which case the indentation of the previous line will be preserved when
-/// outputting new lines.
-#[derive(Clone, PartialEq, Debug)]
-pub struct Block(pub Vec<Line>);
+impl FlatLine {
+    fn push(&mut self, segment: &LineSegment) {
+        match segment {
+            LineSegment::Content(s) => {
+                if let Some(text) = &mut self.text {
+                    text.push_str(s);
+                }
+            }
+            LineSegment::Source(s) => self.source = Some(s.clone()),
+            _ => self.text = None,
+        }
+        self.segments.push(segment.clone());
+    }
+}
 
-impl From<&Block> for String {
-    fn from(v: &Block) -> Self {
-        v.into()
+/// Whether two segments of normalized lines are equal, apart from the blocks in
+/// them, which are added to `pending` to be compared (see `PartialEq for Block`).
+fn segments_eq(a: &LineSegment, b: &LineSegment, pending: &mut Vec<(Block, Block, bool)>) -> bool {
+    match (a, b) {
+        (LineSegment::Block(a), LineSegment::Block(b)) => {
+            pending.push((a.clone(), b.clone(), true));
+        }
+        (LineSegment::Condition(a), LineSegment::Condition(b)) => match (&a.value, &b.value) {
+            _ if a.name != b.name => return false,
+            (Some(a), Some(b)) => pending.push((a.clone(), b.clone(), false)),
+            (None, None) => {}
+            _ => return false,
+        },
+        (LineSegment::Group(a), LineSegment::Group(b)) => {
+            if (&a.open, &a.separator, &a.close, a.trailing_separator)
+                != (&b.open, &b.separator, &b.close, b.trailing_separator)
+                || a.items.len() != b.items.len()
+            {
+                return false;
+            }
+            let items = a.items.iter().zip(&b.items);
+            pending.extend(items.map(|(a, b)| (a.clone(), b.clone(), false)));
+        }
+        _ => return a == b,
     }
+    true
 }
 
-impl<T: Into<String>> From<T> for Block {
-    fn from(v: T) -> Self {
-        Block(vec![Line::from(v.into())])
+/// Hash a segment of a normalized line, apart from the blocks in it, which are
+/// added to `pending` to be hashed (see `Hash for Block`).
+fn hash_segment<H: Hasher>(segment: &LineSegment, state: &mut H, pending: &mut Vec<(Block, bool)>) {
+    mem::discriminant(segment).hash(state);
+    match segment {
+        LineSegment::Block(block) => pending.push((block.clone(), true)),
+        LineSegment::Condition(condition) => {
+            condition.name.hash(state);
+            condition.value.is_some().hash(state);
+            pending.extend(condition.value.clone().map(|value| (value, false)));
+        }
+        LineSegment::Group(group) => {
+            group.open.hash(state);
+            group.separator.hash(state);
+            group.close.hash(state);
+            group.trailing_separator.hash(state);
+            group.items.len().hash(state);
+            pending.extend(group.items.iter().map(|item| (item.clone(), false)));
+        }
+        _ => segment.hash(state),
     }
 }
 
-impl fmt::Display for Block {
-    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        self.write_to(f, "")
+/// Whether two escapers escape text the same way, because they're the same built-in
+/// language or the same custom escaper.
+fn same_escaper(a: Option<&Arc<dyn Escaper>>, b: Option<&Arc<dyn Escaper>>) -> bool {
+    match (a, b) {
+        (None, None) => true,
+        (Some(a), Some(b)) => {
+            Arc::ptr_eq(a, b) || a.language().is_some_and(|l| b.language() == Some(l))
+        }
+        _ => false,
     }
 }
 
-impl Block {
-    pub fn empty() -> Self {
-        Block(vec![])
+/// Whether a segment is nothing but spaces and tabs.
+fn is_whitespace(segment: &LineSegment) -> bool {
+    match segment {
+        LineSegment::Content(s) => s.chars().all(|c| c == ' ' || c == '\t'),
+        LineSegment::Source(_) => true,
+        _ => false,
+    }
+}
+
+impl Line {
+    /// The segments of this line, apart from those recording where it came from.
+    fn contents(&self) -> impl Iterator<Item = &LineSegment> {
+        self.0
+            .iter()
+            .filter(|segment| !matches!(segment, LineSegment::Source(_)))
     }
 
-    pub fn write_to(&self, f: &mut fmt::Formatter, prefix: &str) -> fmt::Result {
-        let mut first_line = true;
-        for line in &self.0 {
-            if !first_line {
-                write!(f, "\n{}", prefix).unwrap();
+    /// Whether this line has nothing in it but empty text.
+    fn is_blank(&self) -> bool {
+        self.0.iter().all(|segment| match segment {
+            LineSegment::Content(s) => s.is_empty(),
+            LineSegment::Source(_) => true,
+            _ => false,
+        })
+    }
+
+    /// Whether this line has any text in it apart from whitespace.
+    fn has_text(&self) -> bool {
+        self.0.iter().any(|segment| match segment {
+            LineSegment::Content(s) => !s.trim_matches([' ', '\t']).is_empty(),
+            _ => false,
+        })
+    }
+
+    /// Whether this line is written whenever the block it's in is, rather than
+    /// possibly being removed for a condition, or for being empty apart from blocks
+    /// and placeholders (see `Renderer::remove_empty_placeholder_lines`).
+    fn is_always_written(&self) -> bool {
+        let mut removable = false;
+        for segment in &self.0 {
+            match segment {
+                LineSegment::Condition(_) => return false,
+                LineSegment::Block(_)
+                | LineSegment::Placeholder(_)
+                | LineSegment::Slot(_)
+                | LineSegment::Hoisted(_) => removable = true,
+                _ => {}
             }
-            first_line = false;
-            line.write_to(f, prefix)?;
         }
-        Ok(())
+        !removable
+            || self.has_text()
+            || self.0.iter().any(|segment| {
+                matches!(
+                    segment,
+                    LineSegment::Group(_)
+                        | LineSegment::Wrapped(_)
+                        | LineSegment::Symbol(_)
+                        | LineSegment::EndOfInput
+                )
+            })
     }
 
-    pub fn set<T: Into<Block>>(mut self, placeholder_name: &str, content: T) -> Self {
-        let content: &Block = &content.into();
-        for line in &mut self.0 {
-            line.set(placeholder_name, content);
+    /// Merge each run of text segments into one, leaving out those that are empty.
+    /// Sources in the run are moved after the text, which makes no difference to
+    /// the line written.
+    fn merge_text(segments: Vec<LineSegment>) -> Line {
+        let mut merged = Vec::with_capacity(segments.len());
+        let mut text: Option<Cow<'static, str>> = None;
+        let mut sources = Vec::new();
+        for segment in segments {
+            match segment {
+                LineSegment::Content(s) if s.is_empty() => {}
+                LineSegment::Content(s) => match &mut text {
+                    Some(text) => text.to_mut().push_str(&s),
+                    None => text = Some(s),
+                },
+                LineSegment::Source(_) => sources.push(segment),
+                _ => {
+                    merged.extend(text.take().map(LineSegment::Content));
+                    merged.append(&mut sources);
+                    merged.push(segment);
+                }
+            }
         }
-        self
+        merged.extend(text.map(LineSegment::Content));
+        merged.append(&mut sources);
+        Line(merged)
+    }
+
+    /// Where this line came from, if it was created from a template.
+    pub fn source(&self) -> Option<&Source> {
+        self.0.iter().find_map(|segment| match segment {
+            LineSegment::Source(source) => Some(&**source),
+            _ => None,
+        })
+    }
+
+    /// Whether this line ends with a newline, either its own or that of a block
+    /// embedded at the end of it.
+    fn ends_with_newline(&self) -> bool {
+        match self.0.last() {
+            Some(LineSegment::EndOfInput) => true,
+            Some(LineSegment::Block(block)) => block.ends_with_newline(),
+            _ => false,
+        }
+    }
+
+    /// The block embedded in this line, if the line consists of nothing else apart
+    /// from leading whitespace (as with each line of `Block::join`).
+    fn nested_block_mut(&mut self) -> Option<&mut Block> {
+        match self.0.split_last_mut() {
+            Some((LineSegment::Block(block), rest)) if rest.iter().all(is_whitespace) => {
+                Some(block)
+            }
+            _ => None,
+        }
+    }
+
+    /// The number of spaces and tabs at the start of this line, and the block that
+    /// follows them if the line starts with one, whose indentation is added to them.
+    /// `None` for blank lines.
+    fn indentation(&self) -> Option<(usize, Option<&Block>)> {
+        let mut width = 0;
+        for segment in &self.0 {
+            match segment {
+                LineSegment::Content(s) => {
+                    let text = s.trim_start_matches([' ', '\t']);
+                    width += s.len() - text.len();
+                    if !text.is_empty() {
+                        return Some((width, None));
+                    }
+                }
+                LineSegment::Placeholder(_)
+                | LineSegment::Group(_)
+                | LineSegment::Wrapped(_)
+                | LineSegment::Hoisted(_)
+                | LineSegment::Symbol(_)
+                | LineSegment::Slot(_) => return Some((width, None)),
+                LineSegment::Block(block) => return Some((width, Some(block))),
+                LineSegment::Condition(_) | LineSegment::Align | LineSegment::Source(_) => {}
+                LineSegment::EndOfInput => return None,
+            }
+        }
+        None
+    }
+
+    /// Remove up to `width` spaces and tabs from the start of this line. If there
+    /// are fewer before a block that the line starts with, returns the block and how
+    /// many are left to remove from it.
+    fn dedent(&mut self, mut width: usize) -> Option<(&mut Block, usize)> {
+        for segment in &mut self.0 {
+            if width == 0 {
+                return None;
+            }
+            match segment {
+                LineSegment::Content(s) => {
+                    let text = s.trim_start_matches([' ', '\t']);
+                    let found_text = !text.is_empty();
+                    let strip = width.min(s.len() - text.len());
+                    match s {
+                        Cow::Borrowed(b) => *b = &b[strip..],
+                        Cow::Owned(o) => {
+                            o.drain(..strip);
+                        }
+                    }
+                    width -= strip;
+                    if found_text {
+                        return None;
+                    }
+                }
+                LineSegment::Block(block) => return Some((block, width)),
+                LineSegment::Condition(_) | LineSegment::Align | LineSegment::Source(_) => {}
+                _ => return None,
+            }
+        }
+        None
+    }
+
+    fn has_placeholder(&self, placeholder_name: &str) -> bool {
+        self.0.iter().any(|segment| match segment {
+            LineSegment::Placeholder(p) => p.name == placeholder_name,
+            LineSegment::Condition(c) => c.name == placeholder_name,
+            _ => false,
+        })
+    }
+
+    pub fn set(&mut self, placeholder_name: &str, content: &Block) {
+        self.fill(placeholder_name, |p| p.fill(content))
+    }
+
+    fn fill<F>(&mut self, placeholder_name: &str, content: F)
+    where
+        F: Fn(&Placeholder) -> Block,
+    {
+        for segment in &mut self.0 {
+            match segment {
+                LineSegment::Placeholder(p) if p.name == placeholder_name => {
+                    let content = content(p);
+                    segment.replace(LineSegment::Block(content));
+                }
+                LineSegment::Condition(c) if c.name == placeholder_name => {
+                    c.value = Some(content(&Placeholder::new(placeholder_name)));
+                }
+                _ => (),
+            }
+        }
+    }
+}
+
+/// How the lines after the first of a block are indented when the block is embedded
+/// part-way through a line.
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug, Default)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(rename_all = "lowercase"))]
+pub enum Indent {
+    /// Line up with the column at which the block was inserted.
+    #[default]
+    Column,
+
+    /// Only inherit the leading indentation of the line the block was inserted into.
+    Leading,
+
+    /// Inherit the leading indentation of the line the block was inserted into, plus
+    /// one more level (see `Renderer::indent_unit`).
+    Hanging,
+
+    /// Don't indent the lines of the block at all.
+    None,
+}
+
+/// A `Block` is one or many lines of text. More blocks can be embedded within a
+/// line, in which case the indentation of the previous line will be preserved when
+/// outputting new lines.
+///
+/// The lines of a block are shared between its clones, so cloning a block (or
+/// inserting it into any number of placeholders) is cheap. Lines are only copied
+/// when a shared block is modified, such as with `set`.
+///
+/// # Serialization
+///
+/// With the `serde` feature, blocks can be serialized and deserialized, along with
+/// their lines and segments, and parsed template `File`s. In JSON, a block is an
+/// object with its `lines`, each of which is an array of segments:
+///
+/// ```json
+/// {
+///   "blocks": [{"lines": [[{"content": "\"body\""}]], "trusted": false}],
+///   "lines": [
+///     [{"content": "fn "}, {"placeholder": {"name": "name", "case": "snake"}}, {"content": "() {"}],
+///     [{"content": "    "}, {"block": 0}],
+///     [{"content": "}"}, "end_of_input"]
+///   ],
+///   "indent": "hanging",
+///   "language": "rust"
+/// }
+/// ```
+///
+/// Each segment is an object with one field, named after the kind of segment in
+/// snake case (`content`, `placeholder`, `block`, `condition`, `group`, `wrapped`,
+/// `symbol`, `slot`, `source` or `hoisted`) and holding its value, apart from
+/// `"align"` and `"end_of_input"`, which are strings.
+///
+/// The blocks a block refers to, however deeply nested (including the values of
+/// conditions and slots, the items of groups and hoisted items), are written once
+/// each in a flat list of `blocks` ahead of its lines, and referred to by their
+/// index in it. Each of them only refers to those before it, and is written like
+/// the outermost block without a list of its own. A slot filled with a block that
+/// contains the slot can't be written this way, and fails to serialize.
+///
+/// The other fields of a block are left out when they have their default value:
+/// `indent` (`"column"`, `"leading"`, `"hanging"` or `"none"`), `prefix` (the line
+/// prefix), `trusted` (`false` for text values), `language` (the escaper, which is
+/// only serialized if it's a `Language`), `hoisted` (an array of key and block
+/// pairs) and `symbol_scope`.
+///
+/// Symbols and slots have an `id`, numbered from 0 in the order they're written, so
+/// that a symbol or slot appearing more than once in a block is deserialized as one
+/// symbol or slot. They are new ones though, so aren't equal to the originals. The
+/// value of a slot is only written where it first appears.
+#[derive(Clone)]
+pub struct Block {
+    lines: Arc<Vec<Line>>,
+    indent: Indent,
+
+    /// Whether this block is code, rather than a text value converted from a string
+    /// which is escaped when inserted into a template.
+    trusted: bool,
+
+    /// Whether the symbols first written in this block are named separately from
+    /// those in the blocks around it.
+    symbol_scope: bool,
+
+    /// The parts of the block that most blocks don't have, kept apart so that blocks
+    /// (and the line segments holding them) stay small.
+    extra: Option<Arc<BlockExtra>>,
+}
+
+#[derive(Clone, Default)]
+struct BlockExtra {
+    prefix: Cow<'static, str>,
+
+    /// Escapes text values inserted into this block.
+    escaper: Option<Arc<dyn Escaper>>,
+
+    /// Items registered with `hoist`, under their keys.
+    hoisted: Vec<(String, Block)>,
+
+    /// Where the placeholders filled in this block are recorded, for blocks created
+    /// from a template of a `File` with coverage (see `File::with_coverage`).
+    coverage: Option<Coverage>,
+}
+
+/// Blocks are equal when their contents are, regardless of how their lines are
+/// nested (see `normalize`), and they are escaped the same way: both are trusted or
+/// neither is, and their escapers are the same `Language` or the same custom one.
+impl PartialEq for Block {
+    fn eq(&self, other: &Self) -> bool {
+        // Only the outermost block is escaped when it's inserted into a template
+        if self.trusted != other.trusted {
+            return false;
+        }
+        // The pairs of blocks left to compare, and whether they're normalized. They
+        // are compared one at a time rather than recursively, so that deeply nested
+        // blocks don't overflow the stack, and the blocks nested in normalized ones
+        // are normalized already.
+        let mut pending = vec![(self.clone(), other.clone(), false)];
+        while let Some((mut a, mut b, normalized)) = pending.pop() {
+            if a.indent != b.indent
+                || a.line_prefix() != b.line_prefix()
+                || a.symbol_scope != b.symbol_scope
+                || !same_escaper(a.escaper(), b.escaper())
+            {
+                return false;
+            }
+            let same_lines = Arc::ptr_eq(&a.lines, &b.lines);
+            if !same_lines && !normalized {
+                a = a.normalize();
+                b = b.normalize();
+            }
+            let (a_hoisted, b_hoisted) = (a.hoisted_items(), b.hoisted_items());
+            if a_hoisted.len() != b_hoisted.len() {
+                return false;
+            }
+            for ((a_key, a_item), (b_key, b_item)) in a_hoisted.iter().zip(b_hoisted) {
+                if a_key != b_key {
+                    return false;
+                }
+                pending.push((a_item.clone(), b_item.clone(), false));
+            }
+            if same_lines {
+                continue;
+            }
+            if a.lines.len() != b.lines.len() {
+                return false;
+            }
+            for (a_line, b_line) in a.lines().zip(b.lines()) {
+                let (mut a_segments, mut b_segments) = (a_line.contents(), b_line.contents());
+                loop {
+                    match (a_segments.next(), b_segments.next()) {
+                        (Some(a), Some(b)) if segments_eq(a, b, &mut pending) => {}
+                        (None, None) => break,
+                        _ => return false,
+                    }
+                }
+            }
+        }
+        true
+    }
+}
+
+impl Eq for Block {}
+
+impl Hash for Block {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        // Hashed one at a time in the same way blocks are compared
+        self.trusted.hash(state);
+        let mut pending = vec![(self.clone(), false)];
+        while let Some((block, normalized)) = pending.pop() {
+            let block = if normalized { block } else { block.normalize() };
+            block.indent.hash(state);
+            block.line_prefix().hash(state);
+            block.symbol_scope.hash(state);
+            block.escaper().and_then(|e| e.language()).hash(state);
+            block.hoisted_items().len().hash(state);
+            for (key, item) in block.hoisted_items() {
+                key.hash(state);
+                pending.push((item.clone(), false));
+            }
+            block.lines.len().hash(state);
+            for line in block.lines() {
+                line.contents().count().hash(state);
+                for segment in line.contents() {
+                    hash_segment(segment, state, &mut pending);
+                }
+            }
+        }
+    }
+}
+
+impl fmt::Debug for Block {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let mut t = f.debug_tuple("Block");
+        t.field(&self.lines);
+        if self.indent != Indent::default() {
+            t.field(&self.indent);
+        }
+        if !self.line_prefix().is_empty() {
+            t.field(&self.line_prefix());
+        }
+        if !self.hoisted_items().is_empty() {
+            t.field(&self.hoisted_items());
+        }
+        t.finish()
+    }
+}
+
+impl Drop for Block {
+    fn drop(&mut self) {
+        // Dropping a deeply nested tree recursively could overflow the stack, so the
+        // lines of any nested blocks that aren't shared elsewhere are moved out onto
+        // a list and dropped one at a time instead.
+        let mut pending = match Arc::get_mut(&mut self.lines) {
+            Some(lines) if !lines.is_empty() => vec![mem::take(lines)],
+            _ => return,
+        };
+        while let Some(lines) = pending.pop() {
+            for line in lines {
+                for segment in line.0 {
+                    if let LineSegment::Block(mut block) = segment {
+                        if let Some(lines) = Arc::get_mut(&mut block.lines) {
+                            pending.push(mem::take(lines));
+                        }
+                    }
+                }
+            }
+        }
+    }
+}
+
+impl Default for Block {
+    fn default() -> Self {
+        Block::empty()
+    }
+}
+
+impl From<&Block> for String {
+    fn from(v: &Block) -> Self {
+        v.to_string()
+    }
+}
+
+/// Converts a string into a block with one line for each line of the string, so that
+/// every line is indented to match the place the block is inserted. Both `\n` and
+/// `\r\n` line endings are recognised, and a trailing line ending becomes an
+/// `EndOfInput`.
+///
+/// The block is a text value, which is escaped when inserted into a template that
+/// has an escaper (see `Block::trusted`).
+impl From<String> for Block {
+    fn from(s: String) -> Self {
+        if !s.contains('\n') {
+            return Block::raw(s);
+        }
+        let (s, terminated) = match s.strip_suffix('\n') {
+            Some(s) => (s.strip_suffix('\r').unwrap_or(s), true),
+            None => (s.as_str(), false),
+        };
+        let mut block = Block::new(
+            s.split('\n')
+                .map(|line| Line::from(line.strip_suffix('\r').unwrap_or(line)))
+                .collect(),
+        );
+        block.trusted = false;
+        if terminated {
+            block.terminated()
+        } else {
+            block
+        }
+    }
+}
+
+impl From<&str> for Block {
+    fn from(s: &str) -> Self {
+        Block::from(String::from(s))
+    }
+}
+
+impl From<&String> for Block {
+    fn from(s: &String) -> Self {
+        Block::from(s.clone())
+    }
+}
+
+impl From<Cow<'_, str>> for Block {
+    fn from(s: Cow<'_, str>) -> Self {
+        Block::from(s.into_owned())
+    }
+}
+
+impl From<char> for Block {
+    fn from(c: char) -> Self {
+        Block::raw(c)
+    }
+}
+
+/// `None` becomes an empty block, so optional parts of a template can be filled
+/// directly from an `Option`.
+impl<T: Into<Block>> From<Option<T>> for Block {
+    fn from(v: Option<T>) -> Self {
+        v.map_or_else(Block::empty, Into::into)
+    }
+}
+
+impl From<Line> for Block {
+    fn from(line: Line) -> Self {
+        Block::new(vec![line])
+    }
+}
+
+impl From<Vec<Line>> for Block {
+    fn from(lines: Vec<Line>) -> Self {
+        Block::new(lines)
+    }
+}
+
+/// Joins the blocks, each starting on a new line (see `Block::join`).
+impl FromIterator<Block> for Block {
+    fn from_iter<T: IntoIterator<Item = Block>>(iter: T) -> Self {
+        Block::join(iter)
+    }
+}
+
+impl FromIterator<Line> for Block {
+    fn from_iter<T: IntoIterator<Item = Line>>(iter: T) -> Self {
+        Block::new(iter.into_iter().collect())
+    }
+}
+
+/// Adds lines to the end of the block.
+impl Extend<Line> for Block {
+    fn extend<T: IntoIterator<Item = Line>>(&mut self, iter: T) {
+        Arc::make_mut(&mut self.lines).extend(iter)
+    }
+}
+
+/// Adds each block on a new line, as with `Block::join`.
+impl Extend<Block> for Block {
+    fn extend<T: IntoIterator<Item = Block>>(&mut self, iter: T) {
+        self.extend(iter.into_iter().map(Line::from))
+    }
+}
+
+/// Concatenates the lines of two blocks (see `Block::concat`).
+impl<T: Into<Block>> Add<T> for Block {
+    type Output = Block;
+
+    fn add(self, other: T) -> Block {
+        Block::concat(vec![self, other.into()])
+    }
+}
+
+impl<T: Into<Block>> AddAssign<T> for Block {
+    fn add_assign(&mut self, other: T) {
+        *self = mem::take(self) + other;
+    }
+}
+
+/// Renders the block with the default `Renderer`.
+///
+/// # Panics
+///
+/// If the block contains a `Slot` that hasn't been filled. Use
+/// `Renderer::try_render` to get a `RenderError` instead.
+impl fmt::Display for Block {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        self.write_to(f, "")
+    }
+}
+
+impl Block {
+    pub fn new(lines: Vec<Line>) -> Self {
+        Block {
+            lines: Arc::new(lines),
+            indent: Indent::default(),
+            trusted: true,
+            symbol_scope: false,
+            extra: None,
+        }
+    }
+
+    fn extra_mut(&mut self) -> &mut BlockExtra {
+        Arc::make_mut(self.extra.get_or_insert_with(Default::default))
+    }
+
+    /// A block replacing this one with `block`, keeping the indentation, line prefix
+    /// and hoisted items of this one.
+    fn replaced_by(&self, mut block: Block) -> Block {
+        block.indent = self.indent;
+        if let Some(extra) = &self.extra {
+            let block_extra = block.extra_mut();
+            block_extra.prefix = extra.prefix.clone();
+            block_extra.hoisted = extra.hoisted.clone();
+        }
+        block
+    }
+
+    /// This block without its line prefix.
+    fn without_prefix(&self) -> Block {
+        let mut block = self.clone();
+        if !block.line_prefix().is_empty() {
+            block.extra_mut().prefix = Cow::Borrowed("");
+        }
+        block
+    }
+
+    pub fn empty() -> Self {
+        Block::new(vec![])
+    }
+
+    /// Create a single line block from a string, without splitting it into lines.
+    /// Any newlines in the string are written out as-is, without indentation.
+    ///
+    /// Like a block converted from a string, this is a text value which is escaped
+    /// when inserted into a template.
+    pub fn raw<T: Into<String>>(content: T) -> Self {
+        let mut block = Block::new(vec![Line::from(content)]);
+        block.trusted = false;
+        block
+    }
+
+    /// Create a block of `text` reflowed into lines of words, each starting with
+    /// `prefix`, that fit within `width` columns. The wrapping happens when the block
+    /// is rendered, taking the column it is inserted at in a template into account.
+    ///
+    /// Paragraphs (separated by blank lines) are kept apart by a line with just the
+    /// prefix on it. Words that are too long to fit are put on a line of their own.
+    pub fn wrapped<T, P>(text: T, width: usize, prefix: P) -> Self
+    where
+        T: Into<Cow<'static, str>>,
+        P: Into<Cow<'static, str>>,
+    {
+        let wrapped = Wrapped {
+            text: text.into(),
+            width,
+            prefix: prefix.into(),
+        };
+        if wrapped.paragraphs().is_empty() {
+            return Block::empty();
+        }
+        Block::new(vec![Line(vec![LineSegment::Wrapped(wrapped)])])
+    }
+
+    /// Register an item to be hoisted out of this block under `key`, such as an
+    /// import needed by the code in it. Wherever `Block::hoisted(key)` is inserted in
+    /// the tree being rendered, it's filled with every item registered under `key`.
+    pub fn hoist<T: Into<Block>>(mut self, key: &str, item: T) -> Self {
+        self.extra_mut().hoisted.push((key.into(), item.into()));
+        self
+    }
+
+    /// A block which is filled when rendered with the items hoisted under `key` by
+    /// all of the blocks in the tree being rendered (including those filling
+    /// placeholders from the `Renderer`'s resolver). The items are sorted by their
+    /// rendered text, with duplicates removed, and each written on its own line.
+    ///
+    /// This is usually set as a placeholder at the top of the outermost template:
+    ///
+    /// ```
+    /// # use jens::Block;
+    /// let body = Block::from("let map = HashMap::new();")
+    ///     .hoist("imports", "use std::collections::HashMap;");
+    /// let file = Block::join(vec![Block::hoisted("imports"), Block::from(""), body]);
+    ///
+    /// assert_eq!(
+    ///     file.to_string(),
+    ///     "use std::collections::HashMap;\n\nlet map = HashMap::new();"
+    /// );
+    /// ```
+    pub fn hoisted(key: &str) -> Self {
+        Block::new(vec![Line(vec![LineSegment::Hoisted(key.into())])])
+    }
+
+    /// Make this block a scope for the names of symbols (see `ident::Symbol`), such
+    /// as the body of a function. Symbols first written in it are numbered after
+    /// the names used in the scopes around it, but independently of other scopes.
+    pub fn symbol_scope(mut self) -> Self {
+        self.symbol_scope = true;
+        self
+    }
+
+    /// Whether this block is a scope for the names of symbols.
+    pub fn is_symbol_scope(&self) -> bool {
+        self.symbol_scope
+    }
+
+    /// Record the placeholders filled in this block to `coverage`.
+    pub(crate) fn with_coverage(mut self, coverage: &Coverage) -> Self {
+        self.extra_mut().coverage = Some(coverage.clone());
+        self
+    }
+
+    fn coverage(&self) -> Option<&Coverage> {
+        self.extra.as_ref()?.coverage.as_ref()
+    }
+
+    /// The items registered with `hoist`.
+    pub(crate) fn hoisted_items(&self) -> &[(String, Block)] {
+        match &self.extra {
+            Some(extra) => &extra.hoisted,
+            None => &[],
+        }
+    }
+
+    /// Mark a block as code that is inserted into templates as-is, even if it was
+    /// converted from a string and the template escapes text values.
+    pub fn trusted<T: Into<Block>>(content: T) -> Self {
+        let mut block = content.into();
+        block.trusted = true;
+        block
+    }
+
+    /// Whether this block is inserted into templates as-is (see `Block::trusted`).
+    pub fn is_trusted(&self) -> bool {
+        self.trusted
+    }
+
+    /// Escape the text values inserted into this block with `set`.
+    pub fn with_escaper<E: Escaper + 'static>(mut self, escaper: E) -> Self {
+        self.extra_mut().escaper = Some(Arc::new(escaper));
+        self
+    }
+
+    /// If this block is a text value, escape its text with `escaper`. The escaped
+    /// block is trusted, so it isn't escaped again.
+    pub fn escaped(&self, escaper: &dyn Escaper) -> Self {
+        if self.trusted {
+            return self.clone();
+        }
+        let text = self.without_prefix().to_string();
+        self.replaced_by(Block::trusted(escaper.escape(&text).into_owned()))
+    }
+
+    /// The escaper of the text values inserted into this block, if it has one.
+    pub(crate) fn escaper(&self) -> Option<&Arc<dyn Escaper>> {
+        self.extra.as_ref()?.escaper.as_ref()
+    }
+
+    /// If this block is a text value, convert its text to `case`. Text values that
+    /// can't be rendered are left as they are.
+    fn converted(&self, case: Case) -> Self {
+        if self.trusted {
+            return self.clone();
+        }
+        let text = match Renderer::new().try_render(&self.without_prefix()) {
+            Ok(text) => text,
+            Err(_) => return self.clone(),
+        };
+        self.replaced_by(Block::from(case.apply(&text)))
+    }
+
+    /// Whether the last line of this block (or of the block embedded at the end of
+    /// it) ends with a newline.
+    pub fn ends_with_newline(&self) -> bool {
+        let mut block = self;
+        loop {
+            match block.lines().last().and_then(|line| line.0.last()) {
+                Some(LineSegment::Block(inner)) => block = inner,
+                Some(LineSegment::EndOfInput) => return true,
+                _ => return false,
+            }
+        }
+    }
+
+    /// Whether rendering this block might leave a line ended, like a block which
+    /// `ends_with_newline`, so that what follows it is indented like the line it
+    /// was embedded in. That includes blocks whose text after their last newline
+    /// may render nothing, or whose last lines may be removed.
+    fn may_end_line(&self) -> bool {
+        let mut pending = vec![self.clone()];
+        while let Some(block) = pending.pop() {
+            for line in block.lines().rev() {
+                for segment in line.0.iter().rev() {
+                    match segment {
+                        LineSegment::Content(s) if !s.is_empty() => break,
+                        LineSegment::Symbol(_) => break,
+                        LineSegment::Block(nested) => pending.push(nested.clone()),
+                        LineSegment::Source(_) | LineSegment::Condition(_) => {}
+                        LineSegment::Content(_) | LineSegment::Align => {}
+                        // Their values aren't known until the block is rendered
+                        _ => return true,
+                    }
+                }
+                if line.is_always_written() {
+                    break;
+                }
+            }
+        }
+        false
+    }
+
+    /// Whether this block has placeholders, conditions or slots whose values are only
+    /// known when it's rendered, and so are escaped by its escaper then, including
+    /// in nested blocks without an escaper of their own.
+    fn fills_when_rendered(&self) -> bool {
+        let mut pending = vec![self.clone()];
+        while let Some(block) = pending.pop() {
+            for segment in block.lines().flat_map(|line| &line.0) {
+                match segment {
+                    LineSegment::Placeholder(_)
+                    | LineSegment::Slot(_)
+                    | LineSegment::Hoisted(_)
+                    | LineSegment::Condition(Condition { value: None, .. }) => return true,
+                    LineSegment::Block(nested) if nested.escaper().is_none() => {
+                        pending.push(nested.clone())
+                    }
+                    LineSegment::Group(group) => pending.extend(
+                        group
+                            .items
+                            .iter()
+                            .filter(|item| item.escaper().is_none())
+                            .cloned(),
+                    ),
+                    _ => {}
+                }
+            }
+        }
+        false
+    }
+
+    /// End the last line of this block with a newline, if it doesn't already.
+    pub fn terminated(mut self) -> Self {
+        if self.lines.is_empty() || self.ends_with_newline() {
+            return self;
+        }
+        let lines = Arc::make_mut(&mut self.lines);
+        lines.last_mut().unwrap().0.push(LineSegment::EndOfInput);
+        self
+    }
+
+    /// Remove the newline ending the last line of this block, if there is one.
+    pub fn unterminated(mut self) -> Self {
+        if !self.ends_with_newline() {
+            return self;
+        }
+        let mut block = &mut self;
+        loop {
+            let segments = &mut Arc::make_mut(&mut block.lines).last_mut().unwrap().0;
+            if let Some(LineSegment::EndOfInput) = segments.last() {
+                segments.pop();
+                break;
+            }
+            match segments.last_mut() {
+                Some(LineSegment::Block(inner)) => block = inner,
+                _ => unreachable!(),
+            }
+        }
+        self
+    }
+
+    /// Set how this block is indented when it is embedded part-way through a line.
+    pub fn with_indent(mut self, indent: Indent) -> Self {
+        self.indent = indent;
+        self
+    }
+
+    /// How this block is indented when it is embedded part-way through a line.
+    pub fn indent_mode(&self) -> Indent {
+        self.indent
+    }
+
+    /// Iterate over the lines of this block.
+    pub fn lines(&self) -> slice::Iter<'_, Line> {
+        self.lines.iter()
+    }
+
+    /// The number of lines this block renders to, including the lines of blocks
+    /// nested inside it. Placeholders and conditions aren't taken into account.
+    pub fn len(&self) -> usize {
+        if self.lines.is_empty() {
+            return 0;
+        }
+        let mut breaks = 0;
+        let mut pending = vec![self];
+        while let Some(block) = pending.pop() {
+            breaks += block.lines.len().saturating_sub(1);
+            for (i, line) in block.lines().enumerate() {
+                // Lines that already end with a newline aren't followed by another
+                if i + 1 < block.lines.len() && line.ends_with_newline() {
+                    breaks -= 1;
+                }
+                for segment in &line.0 {
+                    match segment {
+                        LineSegment::Block(nested) => pending.push(nested),
+                        LineSegment::EndOfInput => breaks += 1,
+                        _ => {}
+                    }
+                }
+            }
+        }
+        breaks + 1 - self.ends_with_newline() as usize
+    }
+
+    /// Whether this block has no lines at all.
+    pub fn is_empty(&self) -> bool {
+        self.lines.is_empty()
+    }
+
+    /// Add a line to the end of this block.
+    pub fn push_line<T: Into<Line>>(&mut self, line: T) {
+        Arc::make_mut(&mut self.lines).push(line.into());
+    }
+
+    /// Start every line of this block, including the lines of blocks nested inside
+    /// it, with `prefix` (such as `"// "` to comment the block out). Whitespace at
+    /// the end of the prefix is left out on blank lines.
+    pub fn prefix_lines<T: Into<Cow<'static, str>>>(mut self, prefix: T) -> Self {
+        let prefix = prefix.into();
+        if prefix.is_empty() {
+            return self;
+        }
+        let extra = self.extra_mut();
+        extra.prefix = if extra.prefix.is_empty() {
+            prefix
+        } else {
+            Cow::Owned(prefix.into_owned() + &extra.prefix)
+        };
+        self
+    }
+
+    /// The text every line of this block starts with (see `prefix_lines`).
+    pub fn line_prefix(&self) -> &str {
+        match &self.extra {
+            Some(extra) => &extra.prefix,
+            None => "",
+        }
+    }
+
+    /// Indent every line of this block, including the lines of blocks nested inside
+    /// it, by `width` spaces. Blank lines are left empty.
+    pub fn indent(self, width: usize) -> Self {
+        self.prefix_lines(" ".repeat(width))
+    }
+
+    /// Remove the leading whitespace shared by every line of this block that isn't
+    /// blank, including the lines of blocks that a line starts with. Spaces and tabs
+    /// are both counted as one character.
+    pub fn dedent(mut self) -> Self {
+        if let Some(width) = self.indentation() {
+            self.dedent_by(width);
+        }
+        self
+    }
+
+    /// The leading whitespace shared by every line of this block that isn't blank.
+    fn indentation(&self) -> Option<usize> {
+        let mut indentation = None;
+        let mut found = |width: usize| {
+            indentation = Some(indentation.map_or(width, |i: usize| i.min(width)));
+        };
+        // Blocks that lines start with, after the whitespace before them
+        let mut pending = vec![(self, 0)];
+        while let Some((block, before)) = pending.pop() {
+            if block.lines.is_empty() {
+                continue;
+            }
+            let prefix = block.line_prefix();
+            if !prefix.trim_start_matches([' ', '\t']).is_empty() {
+                found(before);
+                continue;
+            }
+            let before = before + prefix.len();
+            for line in block.lines() {
+                match line.indentation() {
+                    Some((width, Some(nested))) => pending.push((nested, before + width)),
+                    Some((width, None)) => found(before + width),
+                    None => {}
+                }
+            }
+        }
+        indentation
+    }
+
+    fn dedent_by(&mut self, width: usize) {
+        let mut pending = vec![(self, width)];
+        while let Some((block, mut width)) = pending.pop() {
+            let prefix = block.line_prefix();
+            let strip = width.min(prefix.len() - prefix.trim_start_matches([' ', '\t']).len());
+            if strip > 0 {
+                let prefix = prefix[strip..].to_owned();
+                block.extra_mut().prefix = Cow::Owned(prefix);
+                width -= strip;
+            }
+            if width > 0 {
+                for line in Arc::make_mut(&mut block.lines) {
+                    pending.extend(line.dedent(width));
+                }
+            }
+        }
+    }
+
+    /// Replace each line of this block with the result of `f`. Lines which only
+    /// embed another block (such as the lines of `Block::join`) aren't passed to `f`,
+    /// but the lines of the embedded block are.
+    pub fn map_lines<F: FnMut(Line) -> Line>(mut self, mut f: F) -> Self {
+        self.map_lines_mut(&mut f);
+        self
+    }
+
+    fn map_lines_mut(&mut self, f: &mut dyn FnMut(Line) -> Line) {
+        // The lines left to map in each of the blocks being mapped, innermost last
+        let mut pending = vec![Arc::make_mut(&mut self.lines).iter_mut()];
+        while let Some(lines) = pending.last_mut() {
+            let line = match lines.next() {
+                Some(line) => line,
+                None => {
+                    pending.pop();
+                    continue;
+                }
+            };
+            if line.nested_block_mut().is_none() {
+                *line = f(mem::take(line));
+                continue;
+            }
+            let block = line.nested_block_mut().unwrap();
+            pending.push(Arc::make_mut(&mut block.lines).iter_mut());
+        }
+    }
+
+    /// Keep only the lines of this block for which `f` returns true. Like with
+    /// `map_lines`, the lines of embedded blocks are filtered rather than the lines
+    /// embedding them, which are removed once they have no lines left.
+    pub fn filter_lines<F: FnMut(&Line) -> bool>(mut self, mut f: F) -> Self {
+        self.retain_lines(&mut f);
+        self
+    }
+
+    fn retain_lines(&mut self, f: &mut dyn FnMut(&Line) -> bool) {
+        // For each of the blocks being filtered, innermost last: the line embedding
+        // it (with its lines taken out), the lines left to filter and those kept
+        let lines = mem::take(Arc::make_mut(&mut self.lines));
+        let mut pending = vec![(None, lines.into_iter(), Vec::new())];
+        while let Some((_, lines, kept)) = pending.last_mut() {
+            if let Some(mut line) = lines.next() {
+                if let Some(block) = line.nested_block_mut() {
+                    let lines = mem::take(Arc::make_mut(&mut block.lines));
+                    pending.push((Some(line), lines.into_iter(), Vec::new()));
+                } else if f(&line) {
+                    kept.push(line);
+                }
+                continue;
+            }
+            let (outer, _, kept) = pending.pop().unwrap();
+            match (outer, pending.last_mut()) {
+                (Some(mut outer), Some((_, _, outer_kept))) => {
+                    if !kept.is_empty() {
+                        let block = outer.nested_block_mut().unwrap();
+                        *Arc::make_mut(&mut block.lines) = kept;
+                        outer_kept.push(outer);
+                    }
+                }
+                _ => *Arc::make_mut(&mut self.lines) = kept,
+            }
+        }
+    }
+
+    /// This block with the blocks nested in it flattened into its own lines, and
+    /// runs of text merged, wherever that doesn't change how it renders. Blocks
+    /// which are built differently but render the same way, such as a `join` of
+    /// single lines and the same lines in one block, normalize to the same thing.
+    /// Blocks are compared and hashed in this form.
+    ///
+    /// A nested block spanning several lines is only flattened if the indentation
+    /// of its lines can be worked out ahead of time, so blocks which follow a
+    /// placeholder or a tab, or are indented with `Indent::Hanging`, are kept (and
+    /// normalized in turn). So are blocks with a line prefix, symbol scopes, blocks
+    /// with conditions or alignment markers in them, and blocks that may end with
+    /// a newline. Normalizing a normalized block leaves it the same.
+    pub fn normalize(&self) -> Block {
+        // The blocks being normalized, innermost last, so that deeply nested blocks
+        // don't overflow the stack
+        let mut pending = vec![Normalizing::new(self, true)];
+        loop {
+            let normalizing = pending.last_mut().unwrap();
+            if let Some(nested) = normalizing.next_nested() {
+                pending.push(Normalizing::new(nested, true));
+                continue;
+            }
+            let (block, aligned) = pending.pop().unwrap().finish();
+            match pending.last_mut() {
+                Some(outer) => outer.add_nested(block, aligned),
+                None => return block,
+            }
+        }
+    }
+
+    /// The indentation to give the lines after the first of `nested` (a normalized
+    /// block), if it can be flattened into one of the lines of this block after
+    /// `text`. See `Normalizing::add_nested`.
+    fn flattened_indentation(
+        &self,
+        nested: &Block,
+        split: bool,
+        text: &Option<String>,
+        first: bool,
+    ) -> Option<String> {
+        if !nested.line_prefix().is_empty() || nested.symbol_scope || nested.may_end_line() {
+            return None;
+        }
+        // Values filled in as it's rendered would be escaped differently
+        if nested.escaper().is_some()
+            && !same_escaper(nested.escaper(), self.escaper())
+            && nested.fills_when_rendered()
+        {
+            return None;
+        }
+        let dynamic = nested.lines().any(|line| {
+            line.0.iter().any(|segment| {
+                matches!(
+                    segment,
+                    LineSegment::Condition(_) | LineSegment::Align | LineSegment::EndOfInput
+                )
+            })
+        });
+        // Lines with only whitespace and blocks which render nothing can be removed
+        // (see `Renderer::remove_empty_placeholder_lines`), which works differently
+        // for the first and last lines once they're part of the line around them
+        match (nested.lines.first(), nested.lines.last()) {
+            (Some(first), Some(last)) if !dynamic && first.has_text() && last.has_text() => {}
+            _ => return None,
+        }
+        if nested.lines.len() == 1 {
+            return Some(String::new());
+        }
+        if !split {
+            return None;
+        }
+        let text = text.as_deref().filter(|text| !text.contains('\t'))?;
+        // Indentation is only written ahead of text, so that lines which start with
+        // blocks that render nothing don't end up with trailing whitespace
+        let indentable = nested.lines().skip(1).all(|line| {
+            line.is_blank()
+                || matches!(
+                    line.contents().next(),
+                    Some(LineSegment::Content(s)) if !s.is_empty()
+                )
+        });
+        if !indentable {
+            return None;
+        }
+        // The lines of this block are indented by the indentation of the block,
+        // relative to its line prefix if it has one, and the nested block's lines
+        // by its own indentation mode from there. Past the first line the modes
+        // give the same result, apart from `Indent::None`, which isn't relative.
+        // On the first line, they're indented from whatever is before the block
+        // around them, so the modes must be the same.
+        let outer = match self.line_prefix().is_empty() {
+            true => Some(self.indent),
+            false => None,
+        };
+        match (nested.indent, outer) {
+            (Indent::Hanging, _) => None,
+            (Indent::None, None) | (Indent::None, Some(Indent::None)) => Some(String::new()),
+            (Indent::None, _) => None,
+            (indent, Some(outer)) if first && indent != outer => None,
+            (Indent::Column, _) => Some(replace_chars_with_whitespace(text, None)),
+            (Indent::Leading, Some(_)) if first && !leading_whitespace(text).is_empty() => None,
+            (Indent::Leading, _) => Some(leading_whitespace(text).into()),
+        }
+    }
+
+    /// A hash of the normalized form of this block, for caching generated output.
+    /// Blocks which are equal have the same fingerprint.
+    ///
+    /// The fingerprint is the same between runs, but not between versions of this
+    /// crate or platforms. Symbols and slots are fingerprinted by identity, so
+    /// blocks containing them get a new fingerprint each run. Custom escapers
+    /// aren't fingerprinted, so blocks differing only in them collide.
+    pub fn fingerprint(&self) -> u64 {
+        let mut hasher = Fnv::default();
+        self.hash(&mut hasher);
+        hasher.finish()
+    }
+
+    /// Write the block to `f`, like `Display`. Every line after the first is preceded
+    /// by `prefix`.
+    ///
+    /// # Panics
+    ///
+    /// If the block contains a `Slot` that hasn't been filled.
+    pub fn write_to(&self, f: &mut fmt::Formatter, prefix: &str) -> fmt::Result {
+        Renderer::new().write_or_panic(f, self, prefix)
+    }
+
+    /// Render the block, filling any placeholders that are still unset with values
+    /// looked up from `resolver`.
+    ///
+    /// Blocks returned by the resolver are rendered against the same resolver, so a
+    /// single top-level context can provide values used deep inside nested templates.
+    pub fn render_with(&self, resolver: &dyn Resolver) -> String {
+        Renderer::new().resolver(resolver).render(self)
+    }
+
+    /// Fill a placeholder with `content`, indenting it according to `indent` rather
+    /// than the mode given in the template.
+    pub fn set_indented<T: Into<Block>>(
+        self,
+        placeholder_name: &str,
+        content: T,
+        indent: Indent,
+    ) -> Self {
+        let content = content.into();
+        let escaper = self.escaper().cloned();
+        self.fill(placeholder_name, |p| {
+            p.fill_escaped(&content, escaper.as_deref())
+                .with_indent(indent)
+        })
+    }
+
+    pub fn set<T: Into<Block>>(self, placeholder_name: &str, content: T) -> Self {
+        let content = content.into();
+        let escaper = self.escaper().cloned();
+        self.fill(placeholder_name, |p| {
+            p.fill_escaped(&content, escaper.as_deref())
+        })
+    }
+
+    fn fill<F>(mut self, placeholder_name: &str, content: F) -> Self
+    where
+        F: Fn(&Placeholder) -> Block,
+    {
+        if !self
+            .lines()
+            .any(|line| line.has_placeholder(placeholder_name))
+        {
+            return self;
+        }
+        self.record_fill(placeholder_name);
+        for line in Arc::make_mut(&mut self.lines) {
+            line.fill(placeholder_name, &content);
+        }
+        self
+    }
+
+    /// Record a placeholder being filled, once for each template with a line it's on.
+    fn record_fill(&self, placeholder_name: &str) {
+        let coverage = match self.coverage() {
+            Some(coverage) => coverage,
+            None => return,
+        };
+        let mut sources: Vec<&Source> = Vec::new();
+        for line in self.lines() {
+            let source = match line.source() {
+                Some(source) if line.has_placeholder(placeholder_name) => source,
+                _ => continue,
+            };
+            let template = (&source.template, &source.file);
+            if !sources.iter().any(|s| (&s.template, &s.file) == template) {
+                sources.push(source);
+            }
+        }
+        for source in sources {
+            coverage.record_fill(source, placeholder_name);
+        }
+    }
+
+    /// Run a function that maps over each item in an iterator, then join the results.
+    ///
+    /// Provides a `Position` for checking where the current item is in the list. The
+    /// iterator is consumed lazily, looking one item ahead to tell whether the
+    /// current item is the last.
+    pub fn join_each<T, U, F>(iter: T, mut mapper: F) -> Self
+    where
+        T: IntoIterator<Item = U>,
+        F: FnMut(U, Position) -> Block,
+    {
+        let mut iter = iter.into_iter().peekable();
+        let len = match iter.size_hint() {
+            (lower, Some(upper)) if lower == upper => Some(lower),
+            _ => None,
+        };
+        let mut index = 0;
+        Block::join(iter::from_fn(|| {
+            let item = iter.next()?;
+            let position = Position {
+                index,
+                len,
+                is_first: index == 0,
+                is_last: iter.peek().is_none(),
+            };
+            index += 1;
+            Some(mapper(item, position))
+        }))
     }
 
     /// Run a function that maps over each item in an iterator, then join the results.
     ///
     /// Provides an `IteratorLocation` for checking whether the current item is the
     /// first/last/only/nth item in the list.
+    ///
+    /// Deprecated in favor of `join_each`.
+    #[deprecated]
     pub fn join_map<T, U, F>(iter: T, mapper: F) -> Self
     where
         T: IntoIterator<Item = U>,
         F: Fn(U, IteratorLocation) -> Block,
     {
-        let items: Vec<U> = iter.into_iter().collect();
-        let count = items.len();
-        match count {
-            0 => Block::empty(),
-            1 => mapper(items.into_iter().next().unwrap(), IteratorLocation::Only),
-            count => Block::join(items.into_iter().enumerate().map(|(i, item)| {
-                let loc = match (i, count) {
-                    (0, _) => IteratorLocation::First,
-                    (x, y) if x == y - 1 => IteratorLocation::Last,
-                    (x, _) => IteratorLocation::Nth(x),
-                };
-                mapper(item, loc)
-            })),
-        }
+        Block::join_each(iter, |item, position| mapper(item, position.location()))
     }
 
     /// Repeat a template for each element of some iterable value.
     ///
-    /// Deprecated in favor of `join_map`.
+    /// Deprecated in favor of `join_each`.
     #[deprecated]
     pub fn for_each<T, U, F>(self, iter: T, mapper: F) -> Self
     where
@@ -187,18 +2155,158 @@ impl Block {
         Block::join(iter.into_iter().map(|item| mapper(item, self.clone())))
     }
 
-    /// Join multiple blocks into a single block
+    /// Join multiple blocks into a single block, with each block starting on a new
+    /// line.
+    ///
+    /// Blocks that already end with a newline (see `Block::terminated`) aren't
+    /// followed by another one, so they don't leave blank lines between them.
     pub fn join<T>(blocks: T) -> Block
     where
         T: IntoIterator<Item = Block>,
     {
-        Block(
+        Block::new(
             blocks
                 .into_iter()
                 .map(|block| Line(vec![LineSegment::Block(block)]))
                 .collect(),
         )
     }
+
+    /// Combine multiple blocks into one, with the lines of each block following those
+    /// of the block before. Unlike `join`, the lines aren't nested, except for blocks
+    /// with a line prefix, which would otherwise lose it.
+    ///
+    /// If any of the blocks is a text value, so is the result.
+    pub fn concat<T>(blocks: T) -> Block
+    where
+        T: IntoIterator<Item = Block>,
+    {
+        let mut lines = Vec::new();
+        let mut trusted = true;
+        let mut hoisted = Vec::new();
+        let mut coverage = None;
+        for mut block in blocks {
+            trusted &= block.trusted;
+            if coverage.is_none() {
+                coverage = block.coverage().cloned();
+            }
+            if !block.line_prefix().is_empty() {
+                lines.push(Line(vec![LineSegment::Block(block)]));
+                continue;
+            }
+            match Arc::get_mut(&mut block.lines) {
+                Some(block_lines) => lines.append(block_lines),
+                None => lines.extend(block.lines().cloned()),
+            }
+            hoisted.extend_from_slice(block.hoisted_items());
+        }
+        let mut block = Block::new(lines);
+        block.trusted = trusted;
+        if !hoisted.is_empty() || coverage.is_some() {
+            let extra = block.extra_mut();
+            extra.hoisted = hoisted;
+            extra.coverage = coverage;
+        }
+        block
+    }
+
+    /// Join multiple blocks, each on a new line, with `separator` added to the end
+    /// of every block but the last (such as the commas between the items of a list).
+    pub fn join_with<T, S>(blocks: T, separator: S) -> Block
+    where
+        T: IntoIterator<Item = Block>,
+        S: Into<Block>,
+    {
+        let separator = separator.into();
+        let mut blocks = blocks.into_iter().peekable();
+        let mut lines = Vec::new();
+        while let Some(block) = blocks.next() {
+            let mut segments = vec![LineSegment::Block(block)];
+            if blocks.peek().is_some() {
+                segments.push(LineSegment::Block(separator.clone()));
+            }
+            lines.push(Line(segments));
+        }
+        Block::new(lines)
+    }
+
+    /// Join multiple blocks, each on a new line, with `terminator` added to the end
+    /// of every block (such as the semicolons after a list of statements).
+    pub fn join_terminated<T, S>(blocks: T, terminator: S) -> Block
+    where
+        T: IntoIterator<Item = Block>,
+        S: Into<Block>,
+    {
+        let terminator = terminator.into();
+        Block::new(
+            blocks
+                .into_iter()
+                .map(|block| {
+                    Line(vec![
+                        LineSegment::Block(block),
+                        LineSegment::Block(terminator.clone()),
+                    ])
+                })
+                .collect(),
+        )
+    }
+
+    /// Join multiple blocks, with a blank line between each of them.
+    pub fn join_separated_by_blank_line<T>(blocks: T) -> Block
+    where
+        T: IntoIterator<Item = Block>,
+    {
+        let mut lines = Vec::new();
+        for block in blocks {
+            if !lines.is_empty() {
+                lines.push(Line(vec![]));
+            }
+            lines.push(Line(vec![LineSegment::Block(block)]));
+        }
+        Block::new(lines)
+    }
+
+    /// Join multiple blocks on a single line, with `separator` between them (such as
+    /// `a, b, c`).
+    pub fn join_inline<T, S>(blocks: T, separator: S) -> Block
+    where
+        T: IntoIterator<Item = Block>,
+        S: Into<Block>,
+    {
+        let separator = separator.into();
+        let mut segments = Vec::new();
+        for block in blocks {
+            if !segments.is_empty() {
+                segments.push(LineSegment::Block(separator.clone()));
+            }
+            segments.push(LineSegment::Block(block));
+        }
+        if segments.is_empty() {
+            return Block::empty();
+        }
+        Block::new(vec![Line(segments)])
+    }
+
+    /// A single line made of `cells`, with an alignment marker (`LineSegment::Align`)
+    /// before each cell after the first. Joining rows lines up their cells in
+    /// columns when they are rendered.
+    pub fn row<T>(cells: T) -> Block
+    where
+        T: IntoIterator,
+        T::Item: Into<Block>,
+    {
+        let mut segments = Vec::new();
+        for cell in cells {
+            if !segments.is_empty() {
+                segments.push(LineSegment::Align);
+            }
+            segments.push(LineSegment::Block(cell.into()));
+        }
+        if segments.is_empty() {
+            return Block::empty();
+        }
+        Block::new(vec![Line(segments)])
+    }
 }
 
 impl<'a> From<&'a Template> for Block {
@@ -206,26 +2314,35 @@ impl<'a> From<&'a Template> for Block {
         let mut lines: Vec<Line> = Vec::with_capacity(t.lines.len());
         let indent_ignored = t.indent_ignored;
 
-        for template_line in &t.lines {
+        for (i, template_line) in t.lines.iter().enumerate() {
             let mut segments: Vec<LineSegment> =
-                Vec::with_capacity(template_line.segments.len() + 1);
+                Vec::with_capacity(template_line.segments.len() + 2);
+            segments.push(LineSegment::Source(t.source(i)));
 
             // Add correct amount of whitespace at the beginning of the block
             let indentation_len = template_line.indentation.len();
             if indentation_len > indent_ignored {
                 let indentation: &str = &template_line.indentation[indent_ignored..];
-                segments.push(LineSegment::Content(String::from(indentation)));
+                segments.push(LineSegment::from(indentation));
             }
 
             for template_segment in &template_line.segments {
                 segments.push(match template_segment {
                     Segment::Placeholder(x) => LineSegment::Placeholder(x.clone()),
-                    Segment::Content(x) => LineSegment::Content(x.clone()),
+                    Segment::Content(x) => LineSegment::from(x.as_str()),
+                    Segment::Condition(name) => LineSegment::Condition(Condition::new(name)),
+                    Segment::Align => LineSegment::Align,
+                    // Removed along with the rest of the line ending the section
+                    Segment::SectionEnd(_) => unreachable!(),
+                    Segment::EndOfInput => LineSegment::EndOfInput,
                 })
             }
             lines.push(Line(segments));
         }
-        Block(lines)
+        match t.language {
+            Some(language) => Block::new(lines).with_escaper(language),
+            None => Block::new(lines),
+        }
     }
 }
 
@@ -233,23 +2350,30 @@ impl<'a> From<&'a Template> for Block {
 mod tests {
     use super::*;
 
+    #[test]
+    fn keeps_blocks_small() {
+        let word = mem::size_of::<usize>();
+        assert!(mem::size_of::<Block>() <= 3 * word);
+        assert!(mem::size_of::<LineSegment>() <= 7 * word);
+    }
+
     #[test]
     fn outputs_a_block_with_correct_indentation() {
         use insta::assert_snapshot_matches;
         use std::fmt::Write;
 
-        let arg_list = Block(vec![
+        let arg_list = Block::new(vec![
             Line(vec![LineSegment::from("arg1: string,")]),
             Line(vec![LineSegment::from("arg2: number,")]),
             Line(vec![LineSegment::from("arg3: Object")]),
         ]);
 
-        let function_body = Block(vec![
+        let function_body = Block::new(vec![
             Line(vec![LineSegment::from("body();")]),
             Line(vec![LineSegment::from("body2();")]),
         ]);
 
-        let function = Block(vec![
+        let function = Block::new(vec![
             Line(vec![
                 LineSegment::from("function test("),
                 LineSegment::Block(arg_list),
@@ -270,13 +2394,845 @@ mod tests {
     #[test]
     fn replaces_a_placeholder() {
         use insta::assert_debug_snapshot_matches;
-        let block = Block(vec![Line(vec![
+        let block = Block::new(vec![Line(vec![
             LineSegment::from("A"),
-            LineSegment::Placeholder(String::from("x")),
+            LineSegment::Placeholder("x".into()),
             LineSegment::from("C"),
         ])]);
-        let block = block.set("x", Block(vec![Line(vec![LineSegment::from("B")])]));
+        let block = block.set("x", Block::new(vec![Line(vec![LineSegment::from("B")])]));
 
         assert_debug_snapshot_matches!("block.replaces_a_placeholder", block);
     }
+
+    #[test]
+    fn splits_strings_into_lines() {
+        let block = Block::trusted("a\nb\r\n\nc\n");
+
+        assert_eq!(
+            block,
+            Block::new(vec![
+                Line::from("a"),
+                Line::from("b"),
+                Line::from(""),
+                Line(vec![LineSegment::from("c"), LineSegment::EndOfInput]),
+            ])
+        );
+        assert_eq!(Block::trusted("a"), Block::new(vec![Line::from("a")]));
+    }
+
+    #[test]
+    fn indents_each_line_of_a_multi_line_string() {
+        let block = Block::new(vec![Line(vec![
+            LineSegment::from("  "),
+            LineSegment::Placeholder("x".into()),
+        ])]);
+
+        let split = block.clone().set("x", format!("{}\n{}", "one", "two"));
+        assert_eq!(split.to_string(), "  one\n  two");
+
+        let raw = block.set("x", Block::raw("one\ntwo"));
+        assert_eq!(raw.to_string(), "  one\ntwo");
+    }
+
+    #[test]
+    fn terminates_blocks() {
+        let block = Block::join(vec![Block::from("a"), Block::from("b")]);
+        assert!(!block.ends_with_newline());
+
+        let terminated = block.clone().terminated();
+        assert!(terminated.ends_with_newline());
+        assert_eq!(terminated.to_string(), "a\nb\n");
+        assert_eq!(terminated.clone().terminated(), terminated);
+        assert_eq!(terminated.unterminated(), block);
+
+        let nested = Block::join(vec![Block::from("a"), Block::from("b\n")]);
+        assert!(nested.ends_with_newline());
+        assert_eq!(nested.unterminated().to_string(), "a\nb");
+
+        assert_eq!(Block::empty().terminated(), Block::empty());
+    }
+
+    #[test]
+    fn set_does_not_affect_clones() {
+        let template = Block::new(vec![Line(vec![
+            LineSegment::from("A"),
+            LineSegment::Placeholder("x".into()),
+        ])]);
+        let b = template.clone().set("x", "B");
+        let c = template.clone().set("x", "C");
+
+        assert_eq!(template.to_string(), "A${x}");
+        assert_eq!(b.to_string(), "AB");
+        assert_eq!(c.to_string(), "AC");
+    }
+
+    #[test]
+    fn converts_options() {
+        assert_eq!(Block::from(Some("a")), Block::from("a"));
+        assert_eq!(Block::from(None::<Block>), Block::empty());
+
+        let template = Block::new(vec![Line(vec![
+            LineSegment::from("pub "),
+            LineSegment::Placeholder("x".into()),
+        ])]);
+        assert_eq!(template.set("x", None::<&str>).to_string(), "pub ");
+    }
+
+    #[test]
+    fn joins_with_separators() {
+        let items = || vec![Block::from("a"), Block::from("b"), Block::from("c")];
+
+        assert_eq!(Block::join_with(items(), ",").to_string(), "a,\nb,\nc");
+        assert_eq!(
+            Block::join_terminated(items(), ";").to_string(),
+            "a;\nb;\nc;"
+        );
+        assert_eq!(
+            Block::join_separated_by_blank_line(items()).to_string(),
+            "a\n\nb\n\nc"
+        );
+        assert_eq!(Block::join_inline(items(), ", ").to_string(), "a, b, c");
+
+        assert_eq!(Block::join_with(vec![], ","), Block::empty());
+        assert_eq!(Block::join_inline(vec![], ", "), Block::empty());
+    }
+
+    #[test]
+    fn separators_follow_the_last_line_of_each_block() {
+        let items = vec![
+            Block::join(vec![Block::from("f("), Block::from("    x)")]),
+            Block::from("y"),
+        ];
+
+        assert_eq!(Block::join_with(items, ",").to_string(), "f(\n    x),\ny");
+    }
+
+    #[test]
+    fn joins_terminated_blocks_separated_by_blank_lines() {
+        let items = vec![Block::from("a\n"), Block::from("b\n")];
+
+        assert_eq!(
+            Block::join_separated_by_blank_line(items).to_string(),
+            "a\n\nb\n"
+        );
+    }
+
+    #[test]
+    fn passes_positions_to_join_each() {
+        let mut positions = Vec::new();
+        Block::join_each(vec!["a", "b", "c"], |item, position| {
+            positions.push(position);
+            Block::from(item)
+        });
+
+        assert_eq!(positions.len(), 3);
+        assert!(positions[0].is_first && !positions[0].is_last);
+        assert!(positions[1].is_middle());
+        assert_eq!(positions[1].remaining(), Some(1));
+        assert!(positions[2].is_last);
+        assert_eq!(positions[2].index, 2);
+        assert_eq!(positions[2].len, Some(3));
+    }
+
+    #[test]
+    fn joins_lazy_iterators_of_unknown_length() {
+        let numbers = (0..).take_while(|&n| n < 3);
+        let block = Block::join_each(numbers, |n, position| {
+            assert_eq!(position.len, None);
+            let separator = if position.is_last { "" } else { "," };
+            Block::from(format!("{}{}", n, separator))
+        });
+
+        assert_eq!(block.to_string(), "0,\n1,\n2");
+
+        let only = Block::join_each(0..1, |_, position| {
+            Block::from(position.is_only().to_string())
+        });
+        assert_eq!(only.to_string(), "true");
+    }
+
+    #[test]
+    fn counts_rendered_lines() {
+        let two = Block::join(vec![Block::from("a"), Block::from("b")]);
+
+        assert_eq!(Block::empty().len(), 0);
+        assert!(Block::empty().is_empty());
+        assert_eq!(Block::from("").len(), 1);
+        assert_eq!(Block::from("a\n").len(), 1);
+        assert_eq!(two.len(), 2);
+        assert_eq!(
+            Block::join(vec![two.clone(), two.clone().terminated()]).len(),
+            4
+        );
+        assert_eq!(
+            Block::new(vec![Line(vec![
+                LineSegment::Block(Block::from("a\n")),
+                LineSegment::from("b"),
+            ])])
+            .len(),
+            2
+        );
+    }
+
+    #[test]
+    fn pushes_lines() {
+        let mut block = Block::from("a");
+        let original = block.clone();
+        block.push_line("b");
+        block.extend(vec![Line::from("c"), Line::from("d")]);
+
+        assert_eq!(block.to_string(), "a\nb\nc\nd");
+        assert_eq!(original.to_string(), "a");
+    }
+
+    #[test]
+    fn concatenates_lines() {
+        let block = Block::concat(vec![
+            Block::from("a\nb"),
+            Block::from("c").prefix_lines("// "),
+            Block::empty(),
+            Block::from("d"),
+        ]);
+
+        assert_eq!(block.len(), 4);
+        assert_eq!(block.lines().count(), 4);
+        assert_eq!(block.to_string(), "a\nb\n// c\nd");
+    }
+
+    #[test]
+    fn dedents_nested_blocks() {
+        let block = Block::join(vec![
+            Block::from("    if x {"),
+            Block::from("        y();\n\n    }"),
+            Block::new(vec![Line(vec![
+                LineSegment::from("  "),
+                LineSegment::Block(Block::from("  z")),
+            ])]),
+        ]);
+
+        assert_eq!(block.dedent().to_string(), "if x {\n    y();\n\n}\nz");
+        assert_eq!(Block::from("a\n b").dedent().to_string(), "a\n b");
+        assert_eq!(Block::from("  a").indent(2).dedent().to_string(), "a");
+    }
+
+    #[test]
+    fn maps_and_filters_nested_lines() {
+        let block = Block::join(vec![
+            Block::from("keep a"),
+            Block::join(vec![Block::from("drop b"), Block::from("keep c")]),
+            Block::join(vec![Block::from("drop d")]),
+        ]);
+
+        let filtered = block
+            .clone()
+            .filter_lines(|line| line != &Line::from("drop b") && line != &Line::from("drop d"));
+        assert_eq!(filtered.to_string(), "keep a\nkeep c");
+
+        let mapped = block.map_lines(|mut line| {
+            line.0.insert(0, LineSegment::from("- "));
+            line
+        });
+        assert_eq!(mapped.to_string(), "- keep a\n- drop b\n- keep c\n- drop d");
+    }
+
+    #[test]
+    fn manipulates_deeply_nested_blocks() {
+        let mut block = Block::join(vec![Block::from("  a"), Block::from("  drop")]);
+        for _ in 0..100_000 {
+            block = Block::join(vec![block]);
+        }
+        let block = Block::join(vec![block, Block::from("  b")]);
+
+        let block = block
+            .dedent()
+            .filter_lines(|line| line != &Line::from("drop"))
+            .map_lines(|line| {
+                Line(vec![
+                    LineSegment::from("- "),
+                    LineSegment::Block(line.into()),
+                ])
+            });
+        assert_eq!(block.to_string(), "- a\n- b");
+    }
+
+    #[test]
+    fn converts_blocks_to_strings() {
+        let block = Block::join(vec![Block::from("a"), Block::from("b")]);
+
+        assert_eq!(String::from(&block), "a\nb");
+    }
+
+    #[test]
+    fn collects_blocks_and_lines() {
+        let joined: Block = vec!["a", "b"].into_iter().map(Block::from).collect();
+        assert_eq!(joined.to_string(), "a\nb");
+
+        let lines: Block = vec!["a", "b"].into_iter().map(Line::from).collect();
+        assert_eq!(lines.to_string(), "a\nb");
+
+        let line: Line = vec![LineSegment::from("a"), Placeholder::new("b").into()]
+            .into_iter()
+            .collect();
+        assert_eq!(line.to_string(), "a${b}");
+    }
+
+    #[test]
+    fn extends_blocks_and_lines() {
+        let mut block = Block::default();
+        block.extend(vec![Line::from("a")]);
+        block.extend(vec![Block::from("b\nc")]);
+        assert_eq!(block.to_string(), "a\nb\nc");
+
+        let mut line = Line::default();
+        line.extend(vec![
+            LineSegment::from("a"),
+            LineSegment::from(Block::from("b")),
+        ]);
+        assert_eq!(line.to_string(), "ab");
+    }
+
+    #[test]
+    fn adds_blocks() {
+        let mut block = Block::from("a") + "b" + Block::from("c\nd");
+        assert_eq!(block.to_string(), "a\nb\nc\nd");
+
+        block += Line::from("e");
+        assert_eq!(block.to_string(), "a\nb\nc\nd\ne");
+    }
+
+    #[test]
+    fn displays_lines_and_segments() {
+        let line = Line(vec![
+            LineSegment::from("f("),
+            LineSegment::from(Block::from("a,\nb")),
+            LineSegment::from(")"),
+        ]);
+
+        assert_eq!(line.to_string(), "f(a,\n  b)");
+        assert_eq!(LineSegment::Placeholder("x".into()).to_string(), "${x}");
+        assert_eq!(LineSegment::EndOfInput.to_string(), "\n");
+    }
+
+    #[test]
+    fn hashes_equal_blocks_equally() {
+        use std::collections::HashSet;
+
+        let mut set = HashSet::new();
+        set.insert(Block::trusted("a\nb"));
+        set.insert(Block::trusted("a\nb"));
+        set.insert(Block::trusted("a\nb").with_indent(Indent::None));
+
+        assert_eq!(set.len(), 2);
+        assert!(set.contains(&Block::from(vec![Line::from("a"), Line::from("b")])));
+        assert!(!set.contains(&Block::from("a\nb")));
+        assert_eq!(Block::default(), Block::empty());
+    }
+
+    #[test]
+    fn escapes_text_values_for_the_template_language() {
+        let f = crate::File::parse(
+            "@language json\nmain = { \"key\": \"${key}\", \"value\": ${value} }\n",
+        )
+        .unwrap();
+        let value = f.template("main").set("key", "a").set("value", "1");
+        let block = f
+            .template("main")
+            .set("key", "say \"hi\"\n")
+            .set("value", value);
+
+        assert_eq!(
+            block.to_string(),
+            "{ \"key\": \"say \\\"hi\\\"\\n\", \"value\": { \"key\": \"a\", \"value\": 1 } }"
+        );
+    }
+
+    #[test]
+    fn does_not_escape_trusted_blocks() {
+        let template = Block::new(vec![Line(vec![
+            LineSegment::from("<p>"),
+            LineSegment::Placeholder("x".into()),
+            LineSegment::from("</p>"),
+        ])])
+        .with_escaper(crate::Language::Html);
+
+        assert_eq!(
+            template.clone().set("x", "<b>").to_string(),
+            "<p>&lt;b&gt;</p>"
+        );
+        assert_eq!(
+            template.set("x", Block::trusted("<b>")).to_string(),
+            "<p><b></p>"
+        );
+        assert!(!Block::from("x").is_trusted());
+        assert!(Block::join(vec![Block::from("x")]).is_trusted());
+        assert!(!(Block::from("x") + Block::empty()).is_trusted());
+    }
+
+    #[test]
+    fn uses_custom_escapers() {
+        let template = Block::new(vec![Line(vec![LineSegment::Placeholder("x".into())])])
+            .with_escaper(|s: &str| s.replace('\'', "''"));
+
+        assert_eq!(template.set("x", "it's").to_string(), "it''s");
+    }
+
+    #[test]
+    fn escapes_values_from_the_resolver() {
+        let f =
+            crate::File::parse("@language json\nmain =\n  ${?v}{\"k\": \"${v}\"}\n--\n").unwrap();
+        let scope = crate::Scope::new().set("v", "a\"b");
+
+        let expected = "{\"k\": \"a\\\"b\"}";
+        assert_eq!(f.template("main").set("v", "a\"b").to_string(), expected);
+        assert_eq!(f.template("main").render_with(&scope), expected);
+
+        // Including in blocks nested in the template, which have no escaper of their own
+        let nested = f.template("main").set(
+            "v",
+            Block::new(vec![Line(vec![
+                LineSegment::from("<"),
+                LineSegment::Placeholder("v".into()),
+                LineSegment::from(">"),
+            ])]),
+        );
+        assert_eq!(nested.render_with(&scope), "{\"k\": \"<a\\\"b>\"}");
+
+        let scope = crate::Scope::new().set("v", Block::trusted("a\"b"));
+        assert_eq!(f.template("main").render_with(&scope), "{\"k\": \"a\"b\"}");
+    }
+
+    #[test]
+    fn converts_the_case_of_placeholder_values() {
+        let block = crate::File::parse("main = struct ${name:pascal} { ${name:snake}: u8 }")
+            .unwrap()
+            .template("main");
+        let scope = crate::Scope::new().set("name", "userId");
+
+        assert_eq!(
+            block.clone().set("name", "http request").to_string(),
+            "struct HttpRequest { http_request: u8 }"
+        );
+        assert_eq!(block.render_with(&scope), "struct UserId { user_id: u8 }");
+    }
+
+    #[test]
+    fn converts_the_case_of_text_values_before_escaping_them() {
+        let block = crate::File::parse("@language html\nmain = <${name:snake}>")
+            .unwrap()
+            .template("main");
+        let scope = crate::Scope::new().set("name", "A<B");
+
+        assert_eq!(block.clone().set("name", "a<b").to_string(), "<a_b>");
+        let indented = block.clone().set_indented("name", "FooBar", Indent::Column);
+        assert_eq!(indented.to_string(), "<foo_bar>");
+        assert_eq!(block.render_with(&scope), "<a_b>");
+    }
+
+    #[test]
+    fn only_converts_the_case_of_text_values() {
+        let f = crate::File::parse("main = let ${a:snake} = ${b:pascal};\nfoo = foo_${y}").unwrap();
+        let scope = crate::Scope::new().set("y", "bar");
+
+        // Placeholders in blocks are still filled
+        let block = f.template("main").set("a", "x").set("b", f.template("foo"));
+        assert_eq!(block.render_with(&scope), "let x = foo_bar;");
+
+        // Symbols are named along with the others in the output
+        let (tmp, other) = (Symbol::new("tmp"), Symbol::new("tmp"));
+        let block = Block::join(vec![
+            f.template("main").set("a", &tmp).set("b", "y"),
+            f.template("main").set("a", &other).set("b", &tmp),
+        ]);
+        assert_eq!(block.to_string(), "let tmp_1 = Y;\nlet tmp_2 = tmp_1;");
+
+        // Slots can still be filled later
+        let slot = Slot::new("b");
+        let block = f.template("main").set("a", "x").set("b", &slot);
+        slot.fill("Later");
+        assert_eq!(block.to_string(), "let x = Later;");
+    }
+
+    #[test]
+    fn records_the_source_of_template_lines() {
+        let file = crate::File::parse("a = one\nb =\n  two\n\n  three\n--\n").unwrap();
+        let block = file.template("b");
+        let lines: Vec<_> = block
+            .lines()
+            .map(|line| line.source().unwrap().to_string())
+            .collect();
+        assert_eq!(lines, vec!["b (line 3)", "b (line 4)", "b (line 5)"]);
+
+        // Where a line came from doesn't affect equality
+        assert_eq!(file.template("a"), Block::trusted("one"));
+    }
+
+    #[test]
+    fn compares_blocks_by_their_normalized_form() {
+        let joined = Block::join(vec![Block::from("a"), Block::from("b\nc")]);
+        let flat = Block::trusted("a\nb\nc");
+        assert_eq!(joined, flat);
+        assert_eq!(joined.fingerprint(), flat.fingerprint());
+        assert_ne!(joined.fingerprint(), Block::from("a\nb").fingerprint());
+
+        let lines: Vec<_> = joined.normalize().lines().cloned().collect();
+        assert_eq!(
+            lines,
+            vec![Line::from("a"), Line::from("b"), Line::from("c")]
+        );
+    }
+
+    #[test]
+    fn compares_how_blocks_are_escaped() {
+        use crate::{File, Renderer, Scope};
+
+        // Text values are escaped when they're inserted, and code isn't
+        assert_ne!(Block::from("\""), Block::trusted("\""));
+        assert_ne!(
+            Block::from("\"").fingerprint(),
+            Block::trusted("\"").fingerprint()
+        );
+
+        let template = |language: &str| {
+            let file = File::parse(&format!("@language {}\nt = \"${{x}}\"", language));
+            file.unwrap().template("t")
+        };
+        assert_eq!(template("json"), template("json"));
+        assert_ne!(template("json"), template("html"));
+        assert_ne!(
+            template("json").fingerprint(),
+            template("html").fingerprint()
+        );
+
+        let quote = |s: &str| s.replace('\'', "''");
+        let custom = Block::trusted("a").with_escaper(quote);
+        assert_eq!(custom, custom.clone());
+        assert_ne!(custom, Block::trusted("a").with_escaper(quote));
+
+        // Blocks with placeholders filled as they're rendered keep their escaper
+        let joined = Block::join(vec![template("json"), template("html")]);
+        let scope = Scope::new().set("x", "<\">");
+        let renderer = Renderer::new().resolver(&scope);
+        assert_eq!(
+            renderer.render(&joined.normalize()),
+            renderer.render(&joined)
+        );
+        assert_eq!(renderer.render(&joined), "\"<\\\">\"\n\"&lt;&quot;&gt;\"");
+    }
+
+    #[test]
+    fn flattens_nested_lines_with_their_indentation() {
+        let args = |indent| Block::from("a,\nb").with_indent(indent);
+        let call = |indent| {
+            Line(vec![
+                LineSegment::from("  call("),
+                LineSegment::Block(args(indent)),
+                LineSegment::from(")"),
+            ])
+        };
+        let lines = |block: Block| -> Vec<String> {
+            block
+                .normalize()
+                .lines()
+                .map(|l| format!("{:?}", l))
+                .collect()
+        };
+
+        assert_eq!(
+            lines(Block::new(vec![Line::from("{"), call(Indent::Column)])),
+            [
+                r#"Line([Content("{")])"#,
+                r#"Line([Content("  call(a,")])"#,
+                r#"Line([Content("       b)")])"#
+            ]
+        );
+        assert_eq!(
+            lines(Block::new(vec![Line::from("{"), call(Indent::Leading)]))[1..],
+            [
+                r#"Line([Content("  call(a,")])"#,
+                r#"Line([Content("  b)")])"#
+            ]
+        );
+
+        // On the first line, the indentation depends on what the block is inserted
+        // after, so it's only flattened if the block is indented the same way
+        assert_eq!(lines(Block::from(call(Indent::Column))).len(), 2);
+        assert_eq!(lines(Block::from(call(Indent::Leading))).len(), 1);
+    }
+
+    #[test]
+    fn keeps_nested_blocks_that_cant_be_flattened() {
+        let nested = |block: Block| {
+            Block::join(vec![
+                Block::from("x"),
+                Block::new(vec![Line(vec![
+                    LineSegment::Placeholder("p".into()),
+                    LineSegment::Block(block),
+                ])]),
+            ])
+        };
+        let flattened = |block: &Block| {
+            !block
+                .normalize()
+                .lines()
+                .any(|line| line.0.iter().any(|s| matches!(s, LineSegment::Block(_))))
+        };
+
+        assert!(flattened(&nested(Block::from("a"))));
+        assert!(!flattened(&nested(Block::from("a\nb"))));
+        assert!(!flattened(&Block::join(vec![
+            Block::from("a\nb").with_indent(Indent::Hanging)
+        ])));
+        assert!(!flattened(&Block::join(vec![
+            Block::from("a").prefix_lines("// ")
+        ])));
+        assert!(!flattened(&Block::join(
+            vec![Block::from("a").terminated()]
+        )));
+        assert!(!flattened(&Block::join(vec![Block::from("  ")])));
+    }
+
+    #[test]
+    fn normalizing_does_not_change_how_blocks_render() {
+        use crate::{Renderer, Scope};
+
+        let placeholder = |name: &str| LineSegment::Placeholder(name.into());
+        let text = |s: &str| LineSegment::from(s.to_owned());
+        let nested = |block: Block| LineSegment::Block(block);
+        let multi = || Block::from("a,\n\n  b");
+        let blocks = vec![
+            Block::join(vec![
+                Block::from("x"),
+                multi(),
+                Block::empty(),
+                Block::from(""),
+            ]),
+            Block::new(vec![
+                Line(vec![text("\tf("), nested(multi()), text(")")]),
+                Line(vec![
+                    text("  f("),
+                    nested(multi().with_indent(Indent::Leading)),
+                ]),
+                Line(vec![
+                    text("  "),
+                    nested(multi().with_indent(Indent::Hanging)),
+                ]),
+                Line(vec![
+                    text("é😀("),
+                    nested(multi()),
+                    text(", "),
+                    nested(multi()),
+                ]),
+                Line(vec![placeholder("e"), nested(multi()), placeholder("v")]),
+                Line(vec![text("g("), nested(multi().with_indent(Indent::None))]),
+            ]),
+            Block::new(vec![Line(vec![
+                text("  "),
+                nested(Block::join(vec![
+                    Block::new(vec![Line(vec![text("  "), placeholder("e")])]),
+                    Block::from("y\nz"),
+                ])),
+            ])])
+            .with_indent(Indent::Leading),
+            Block::new(vec![Line(vec![
+                text("h("),
+                nested(Block::new(vec![
+                    Line(vec![text("i"), placeholder("v")]),
+                    Line(vec![placeholder("e"), text("j")]),
+                    Line(vec![text("  "), placeholder("e")]),
+                    Line(vec![text("k")]),
+                ])),
+            ])]),
+            Block::from("l\nm").prefix_lines("// ").terminated() + Block::from("n"),
+            Block::join(vec![
+                crate::File::parse("t =\n  f(${args})\n  g\n--\n")
+                    .unwrap()
+                    .template("t")
+                    .set(
+                        "args",
+                        Block::join(vec![Block::from("a,"), Block::from("b")]),
+                    ),
+                Block::from("o\np"),
+            ]),
+            // Lines after ones which may be removed can be the first ones written
+            Block::from(Line(vec![nested(
+                Block::new(vec![
+                    Line(vec![LineSegment::Condition(Condition {
+                        name: "c".into(),
+                        value: None,
+                    })]),
+                    Line(vec![nested(multi())]),
+                ])
+                .with_indent(Indent::Hanging),
+            )])),
+            Block::from(Line(vec![
+                text("b c"),
+                nested(
+                    Block::new(vec![
+                        Line(vec![placeholder("e")]),
+                        Line(vec![nested(multi())]),
+                    ])
+                    .with_indent(Indent::None),
+                ),
+            ])),
+            // A line ended by a block followed by ones which render nothing
+            Block::from(Line(vec![
+                nested(Block::from(Line(vec![
+                    text("  d"),
+                    nested(Block::from(Line(vec![
+                        nested(Block::from(Line(vec![LineSegment::EndOfInput]))),
+                        nested(Block::empty()),
+                    ]))),
+                ]))),
+                nested(Block::from("b c")),
+            ])),
+            // Alignment markers in nested blocks
+            Block::from(Line(vec![nested(Block::new(vec![
+                Line(vec![
+                    nested(Block::from("b c\n  d")),
+                    nested(Block::from(Line(vec![LineSegment::Align]))),
+                ]),
+                Line(vec![nested(Block::from(Line(vec![
+                    LineSegment::Align,
+                    nested(Block::from(", ")),
+                ])))]),
+            ]))])),
+        ];
+        let scope = Scope::new().set("e", "").set("v", "v1\nv2").set("c", "");
+        let renderers = vec![
+            Renderer::new().resolver(&scope),
+            Renderer::new()
+                .resolver(&scope)
+                .tab_width(4)
+                .indent_unit("\t"),
+            Renderer::new()
+                .resolver(&scope)
+                .remove_empty_placeholder_lines(true),
+            Renderer::new().resolver(&scope).source_comments("// ", ""),
+        ];
+        for block in &blocks {
+            for renderer in &renderers {
+                let context =
+                    Block::new(vec![Line(vec![text("  let x = "), nested(block.clone())])]);
+                assert_eq!(renderer.render(&block.normalize()), renderer.render(block));
+                assert_eq!(
+                    renderer.render(&Block::from(Line(vec![
+                        text("  let x = "),
+                        nested(block.normalize())
+                    ]))),
+                    renderer.render(&context)
+                );
+            }
+        }
+    }
+
+    /// A block built at random from the kinds of segments and nesting that
+    /// `normalize` treats differently.
+    fn random_block(next: &mut impl FnMut(usize) -> usize, depth: usize) -> Block {
+        const TEXT: [&str; 8] = ["a", "  ", "\t", "b c", "", "  d", "(", ", "];
+        let mut lines = Vec::new();
+        for _ in 0..1 + next(3) {
+            let mut segments = Vec::new();
+            for _ in 0..next(4) {
+                segments.push(match next(if depth == 0 { 10 } else { 14 }) {
+                    0..=4 => LineSegment::from(TEXT[next(TEXT.len())]),
+                    5 => LineSegment::Placeholder(["e", "v", "n"][next(3)].into()),
+                    6 => LineSegment::EndOfInput,
+                    7 => LineSegment::Condition(Condition {
+                        name: "c".into(),
+                        value: None,
+                    }),
+                    8 => LineSegment::Align,
+                    9 => LineSegment::Block(Block::from("x\ny")),
+                    _ => LineSegment::Block(random_block(next, depth - 1)),
+                });
+            }
+            lines.push(Line(segments));
+        }
+        let block = Block::new(lines).with_indent(
+            [
+                Indent::Column,
+                Indent::Leading,
+                Indent::Hanging,
+                Indent::None,
+            ][next(4)],
+        );
+        match next(8) {
+            0 => block.prefix_lines("// "),
+            1 => block.terminated(),
+            2 => block.with_escaper(crate::Language::Json),
+            _ => block,
+        }
+    }
+
+    #[test]
+    fn normalizing_random_blocks_is_idempotent_and_keeps_their_output() {
+        use crate::{Renderer, Scope};
+
+        // A fixed xorshift sequence, so failures can be reproduced
+        let mut state = 0x2545_f491_4f6c_dd1d_u64;
+        let mut next = |n: usize| {
+            state ^= state << 13;
+            state ^= state >> 7;
+            state ^= state << 17;
+            (state % n as u64) as usize
+        };
+        let scope = Scope::new()
+            .set("e", "")
+            .set("v", "v1\n\"v2")
+            .set("n", Block::from("n").terminated())
+            .set("c", "");
+        let renderers = vec![
+            Renderer::new().resolver(&scope),
+            Renderer::new().resolver(&scope).tab_width(4),
+            Renderer::new()
+                .resolver(&scope)
+                .remove_empty_placeholder_lines(true),
+            Renderer::new().resolver(&scope).max_blank_lines(0),
+            Renderer::new()
+                .resolver(&scope)
+                .max_blank_lines(0)
+                .remove_empty_placeholder_lines(true)
+                .tab_width(4),
+        ];
+        for _ in 0..2000 {
+            let block = random_block(&mut next, 3);
+            let normalized = block.normalize();
+            assert_eq!(
+                format!("{:?}", normalized.normalize()),
+                format!("{:?}", normalized),
+                "{:?}",
+                block
+            );
+            assert_eq!(block, normalized, "{:?}", block);
+            for renderer in &renderers {
+                assert_eq!(
+                    renderer.render(&normalized),
+                    renderer.render(&block),
+                    "{:?}",
+                    block
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn normalizes_blocks_nested_in_flattened_ones_again() {
+        let wrapper = Block::new(vec![Line(vec![
+            LineSegment::Block(Block::join(vec![Block::from("a,"), Block::from("b")])),
+            LineSegment::from(")"),
+        ])])
+        .with_indent(Indent::Hanging);
+        let outer = Block::new(vec![
+            Line::from("x = foo("),
+            Line(vec![LineSegment::Block(wrapper)]),
+        ]);
+
+        let normalized = outer.normalize();
+        assert_eq!(normalized.lines().len(), 3);
+        assert_eq!(
+            format!("{:?}", normalized.normalize()),
+            format!("{:?}", normalized)
+        );
+        assert_eq!(outer.to_string(), normalized.to_string());
+    }
 }
@@ -0,0 +1,597 @@
+//! `Serialize` and `Deserialize` for the parts of a block which can't be derived.
+//! See the documentation of `Block` for the format.
+
+use crate::{
+    block::{Block, BlockExtra, Indent, Line, LineSegment, Slot},
+    escape::Language,
+    ident::Symbol,
+};
+use serde::{
+    de::{self, SeqAccess, Visitor},
+    ser::{self, SerializeSeq},
+    Deserialize, Deserializer, Serialize, Serializer,
+};
+use std::{
+    borrow::Cow,
+    cell::RefCell,
+    collections::{HashMap, HashSet},
+    fmt,
+    marker::PhantomData,
+    ptr,
+    sync::Arc,
+};
+
+thread_local! {
+    static IDS: RefCell<Ids> = RefCell::default();
+}
+
+/// Identifies a block by its lines and the rest of its fields. Blocks with the same
+/// key are written the same way, so they're only written once.
+type BlockKey = (*const Vec<Line>, *const BlockExtra, Indent, bool, bool);
+
+fn block_key(block: &Block) -> BlockKey {
+    let extra = block.extra.as_ref().map_or(ptr::null(), Arc::as_ptr);
+    let lines = Arc::as_ptr(&block.lines);
+    (
+        lines,
+        extra,
+        block.indent,
+        block.trusted,
+        block.symbol_scope,
+    )
+}
+
+/// The ids of the symbols, slots and blocks in the block being serialized or
+/// deserialized, so that each is only created once however often it appears.
+#[derive(Default)]
+struct Ids {
+    /// How many blocks (or symbols or slots) are being serialized or deserialized,
+    /// one inside another. The ids are forgotten once the outermost is done.
+    depth: usize,
+
+    /// The ids given to the symbols and slots written, by their identity.
+    written_symbols: HashMap<usize, usize>,
+    written_slots: HashMap<*const (), usize>,
+
+    /// The ids of the blocks in the table of the outermost block being written, and
+    /// the id of the one being written (the outermost block's is the table's length).
+    written_blocks: Option<HashMap<BlockKey, usize>>,
+    writing: usize,
+
+    /// The symbols and slots read, by id.
+    read_symbols: HashMap<usize, Symbol>,
+    read_slots: HashMap<usize, Slot>,
+
+    /// The blocks read so far from the table of the outermost block being read.
+    read_blocks: Option<Vec<Block>>,
+}
+
+/// Run `f` with the same ids as whatever it's nested in, if anything.
+fn with_ids<T, F: FnOnce() -> T>(f: F) -> T {
+    struct Guard;
+
+    impl Drop for Guard {
+        fn drop(&mut self) {
+            IDS.with(|ids| {
+                let mut ids = ids.borrow_mut();
+                ids.depth -= 1;
+                if ids.depth == 0 {
+                    *ids = Ids::default();
+                }
+            });
+        }
+    }
+
+    IDS.with(|ids| ids.borrow_mut().depth += 1);
+    let _guard = Guard;
+    f()
+}
+
+/// The id for `key` in `written`, and whether it was just given out.
+fn written_id<K: Eq + std::hash::Hash>(written: &mut HashMap<K, usize>, key: K) -> (usize, bool) {
+    let next = written.len();
+    let id = *written.entry(key).or_insert(next);
+    (id, id == next)
+}
+
+/// The blocks that `root` refers to, directly or not, for its table: those nested
+/// in its lines, the values of its conditions and slots, the items of its groups
+/// and its hoisted items. Each block comes after the blocks it refers to, so that
+/// they can be read in order. Also returns the id of each block, by its key.
+fn block_table(root: &Block) -> (Vec<Block>, HashMap<BlockKey, usize>) {
+    let mut table = Vec::new();
+    let mut ids = HashMap::new();
+    let mut visited = HashSet::new();
+    visited.insert(block_key(root));
+    // The blocks left to visit, in reverse, and whether the blocks they refer to
+    // have been added before them
+    let mut pending: Vec<_> = referenced_blocks(root)
+        .into_iter()
+        .rev()
+        .map(|b| (b, false))
+        .collect();
+    while let Some((block, visited_references)) = pending.pop() {
+        let key = block_key(&block);
+        if visited_references {
+            ids.insert(key, table.len());
+            table.push(block);
+        } else if visited.insert(key) {
+            let references = referenced_blocks(&block);
+            pending.push((block, true));
+            pending.extend(references.into_iter().rev().map(|b| (b, false)));
+        }
+    }
+    (table, ids)
+}
+
+/// The blocks written as part of `block` (see `block_table`).
+fn referenced_blocks(block: &Block) -> Vec<Block> {
+    let mut blocks = Vec::new();
+    for segment in block.lines().flat_map(|line| &line.0) {
+        match segment {
+            LineSegment::Block(nested) => blocks.push(nested.clone()),
+            LineSegment::Condition(condition) => blocks.extend(condition.value.clone()),
+            LineSegment::Group(group) => blocks.extend(group.items.iter().cloned()),
+            LineSegment::Slot(slot) => blocks.extend(slot.value()),
+            _ => {}
+        }
+    }
+    blocks.extend(block.hoisted_items().iter().map(|(_, item)| item.clone()));
+    blocks
+}
+
+fn yes() -> bool {
+    true
+}
+
+fn is_true(value: &bool) -> bool {
+    *value
+}
+
+fn is_false(value: &bool) -> bool {
+    !*value
+}
+
+fn is_default<T: Default + PartialEq>(value: &T) -> bool {
+    *value == T::default()
+}
+
+#[derive(Serialize, Deserialize)]
+struct BlockData<'a> {
+    /// Only written for the outermost block, and before its lines, which refer to it.
+    #[serde(default, skip_serializing_if = "BlockTable::is_empty")]
+    blocks: BlockTable<'a>,
+    lines: Cow<'a, [Line]>,
+    #[serde(default, skip_serializing_if = "is_default")]
+    indent: Indent,
+    #[serde(default, skip_serializing_if = "str::is_empty")]
+    prefix: Cow<'a, str>,
+    #[serde(default = "yes", skip_serializing_if = "is_true")]
+    trusted: bool,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    language: Option<Language>,
+    #[serde(default, skip_serializing_if = "<[_]>::is_empty")]
+    hoisted: Cow<'a, [(String, Block)]>,
+    #[serde(default, skip_serializing_if = "is_false")]
+    symbol_scope: bool,
+}
+
+impl<'a> From<&'a Block> for BlockData<'a> {
+    fn from(block: &'a Block) -> Self {
+        BlockData {
+            blocks: BlockTable::default(),
+            lines: Cow::Borrowed(&block.lines),
+            indent: block.indent,
+            prefix: Cow::Borrowed(block.line_prefix()),
+            trusted: block.trusted,
+            language: block.escaper().and_then(|escaper| escaper.language()),
+            hoisted: Cow::Borrowed(block.hoisted_items()),
+            symbol_scope: block.symbol_scope,
+        }
+    }
+}
+
+impl From<BlockData<'_>> for Block {
+    fn from(data: BlockData<'_>) -> Self {
+        let mut block = Block::new(data.lines.into_owned()).prefix_lines(data.prefix.into_owned());
+        block.indent = data.indent;
+        block.trusted = data.trusted;
+        if let Some(language) = data.language {
+            block = block.with_escaper(language);
+        }
+        for (key, item) in data.hoisted.into_owned() {
+            block = block.hoist(&key, item);
+        }
+        block.symbol_scope = data.symbol_scope;
+        block
+    }
+}
+
+/// The blocks the outermost block refers to (see `block_table`). Each is written
+/// like the outermost block, apart from the table, and read into `Ids::read_blocks`
+/// as soon as it's read, since the blocks after it may refer to it.
+#[derive(Default)]
+struct BlockTable<'a>(&'a [Block]);
+
+impl BlockTable<'_> {
+    fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+}
+
+impl Serialize for BlockTable<'_> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let mut seq = serializer.serialize_seq(Some(self.0.len()))?;
+        for (id, block) in self.0.iter().enumerate() {
+            IDS.with(|ids| ids.borrow_mut().writing = id);
+            seq.serialize_element(&BlockData::from(block))?;
+        }
+        IDS.with(|ids| ids.borrow_mut().writing = self.0.len());
+        seq.end()
+    }
+}
+
+impl<'de> Deserialize<'de> for BlockTable<'_> {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        struct TableVisitor<'a>(PhantomData<BlockTable<'a>>);
+
+        impl<'de, 'a> Visitor<'de> for TableVisitor<'a> {
+            type Value = BlockTable<'a>;
+
+            fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+                f.write_str("a list of blocks")
+            }
+
+            fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> Result<Self::Value, A::Error> {
+                while let Some(data) = seq.next_element::<BlockData>()? {
+                    let block = Block::from(data);
+                    IDS.with(|ids| {
+                        ids.borrow_mut()
+                            .read_blocks
+                            .get_or_insert_with(Vec::new)
+                            .push(block)
+                    });
+                }
+                Ok(BlockTable::default())
+            }
+        }
+
+        deserializer.deserialize_seq(TableVisitor(PhantomData))
+    }
+}
+
+/// The outermost block is written along with a table of the blocks it refers to,
+/// which are written as their ids in the table wherever they appear, so that even
+/// deeply nested blocks are written without deeply nested data.
+impl Serialize for Block {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        with_ids(|| {
+            let id = IDS.with(|ids| {
+                let ids = ids.borrow();
+                let written = ids.written_blocks.as_ref()?;
+                Some(
+                    written
+                        .get(&block_key(self))
+                        .filter(|&&id| id < ids.writing)
+                        .copied(),
+                )
+            });
+            match id {
+                Some(Some(id)) => return id.serialize(serializer),
+                Some(None) => {
+                    let message = "a slot is filled with a block that contains the slot";
+                    return Err(ser::Error::custom(message));
+                }
+                None => {}
+            }
+
+            let (table, written) = block_table(self);
+            IDS.with(|ids| ids.borrow_mut().written_blocks = Some(written));
+            let data = BlockData {
+                blocks: BlockTable(&table),
+                ..BlockData::from(self)
+            };
+            let result = data.serialize(serializer);
+            IDS.with(|ids| ids.borrow_mut().written_blocks = None);
+            result
+        })
+    }
+}
+
+impl<'de> Deserialize<'de> for Block {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        with_ids(|| {
+            let reading = IDS.with(|ids| ids.borrow().read_blocks.is_some());
+            if reading {
+                let id = usize::deserialize(deserializer)?;
+                let block = IDS.with(|ids| ids.borrow().read_blocks.as_ref()?.get(id).cloned());
+                return block.ok_or_else(|| {
+                    de::Error::custom(format!("block {} isn't in the blocks before it", id))
+                });
+            }
+
+            IDS.with(|ids| ids.borrow_mut().read_blocks = Some(Vec::new()));
+            let data = BlockData::deserialize(deserializer)?;
+            IDS.with(|ids| ids.borrow_mut().read_blocks = None);
+            Ok(Block::from(data))
+        })
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+struct SymbolData<'a> {
+    id: usize,
+    base: Cow<'a, str>,
+}
+
+impl Serialize for Symbol {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        with_ids(|| {
+            let id = IDS.with(|ids| written_id(&mut ids.borrow_mut().written_symbols, self.id()));
+            let data = SymbolData {
+                id: id.0,
+                base: Cow::Borrowed(self.base()),
+            };
+            data.serialize(serializer)
+        })
+    }
+}
+
+impl<'de> Deserialize<'de> for Symbol {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        with_ids(|| {
+            let data = SymbolData::deserialize(deserializer)?;
+            Ok(IDS.with(|ids| {
+                let mut ids = ids.borrow_mut();
+                let symbol = ids.read_symbols.entry(data.id);
+                symbol
+                    .or_insert_with(|| Symbol::new(data.base.into_owned()))
+                    .clone()
+            }))
+        })
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+struct SlotData<'a> {
+    id: usize,
+    name: Cow<'a, str>,
+
+    /// Only written the first time the slot is, and if it has been filled.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    value: Option<Block>,
+}
+
+impl Serialize for Slot {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        with_ids(|| {
+            let identity = Arc::as_ptr(&self.value) as *const ();
+            let (id, first) =
+                IDS.with(|ids| written_id(&mut ids.borrow_mut().written_slots, identity));
+            let data = SlotData {
+                id,
+                name: Cow::Borrowed(self.name()),
+                value: if first { self.value() } else { None },
+            };
+            data.serialize(serializer)
+        })
+    }
+}
+
+impl<'de> Deserialize<'de> for Slot {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        with_ids(|| {
+            let data = SlotData::deserialize(deserializer)?;
+            Ok(IDS.with(|ids| {
+                let mut ids = ids.borrow_mut();
+                let slot = ids.read_slots.entry(data.id).or_insert_with(|| {
+                    let slot = Slot::new(&data.name);
+                    if let Some(value) = data.value {
+                        slot.fill(value);
+                    }
+                    slot
+                });
+                slot.clone()
+            }))
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        block::{Block, Condition, Group, Indent, Line, LineSegment, Placeholder, Slot},
+        ident::{Case, Symbol},
+        File, Language, Renderer,
+    };
+    use serde_json::json;
+
+    fn nested<T: Into<Block>>(block: T) -> Line {
+        Line(vec![LineSegment::Block(block.into())])
+    }
+
+    fn round_trip(block: &Block) -> Block {
+        serde_json::from_str(&serde_json::to_string(block).unwrap()).unwrap()
+    }
+
+    #[test]
+    fn serializes_blocks_as_documented() {
+        let block = Block::new(vec![
+            Line(vec![
+                LineSegment::from("fn "),
+                LineSegment::Placeholder(Placeholder::new("name").with_case(Case::Snake)),
+                LineSegment::from("() {"),
+            ]),
+            Line(vec![
+                LineSegment::from("    "),
+                LineSegment::Block(Block::from("\"body\"")),
+            ]),
+            Line(vec![LineSegment::from("}"), LineSegment::EndOfInput]),
+        ])
+        .with_indent(Indent::Hanging)
+        .with_escaper(Language::Rust);
+
+        assert_eq!(
+            serde_json::to_value(&block).unwrap(),
+            json!({
+                "blocks": [{"lines": [[{"content": "\"body\""}]], "trusted": false}],
+                "lines": [
+                    [{"content": "fn "}, {"placeholder": {"name": "name", "case": "snake"}}, {"content": "() {"}],
+                    [{"content": "    "}, {"block": 0}],
+                    [{"content": "}"}, "end_of_input"]
+                ],
+                "indent": "hanging",
+                "language": "rust"
+            })
+        );
+        assert_eq!(round_trip(&block), block);
+    }
+
+    #[test]
+    fn round_trips_every_kind_of_segment() {
+        let condition = Condition {
+            name: "c".into(),
+            value: Some(Block::from("yes")),
+        };
+        let block = Block::new(vec![
+            Line(vec![
+                LineSegment::Condition(condition),
+                LineSegment::from("if"),
+            ]),
+            nested(Group::new("(", vec![Block::from("a")], ",", ")").with_trailing_separator()),
+            nested(Block::wrapped("some words", 4, "// ")),
+            nested(Block::row(vec![Block::from("a"), Block::from("b")])),
+            nested(Block::hoisted("imports")),
+            Line(vec![LineSegment::Placeholder(
+                Placeholder::new("p").with_indent(Indent::None),
+            )]),
+        ])
+        .hoist("imports", "use a;")
+        .prefix_lines("# ")
+        .symbol_scope();
+
+        let json = serde_json::to_value(&block).unwrap();
+        assert_eq!(json["prefix"], "# ");
+        assert_eq!(json["symbol_scope"], true);
+        assert_eq!(json["hoisted"][0][0], "imports");
+        let row = json["lines"][3][0]["block"].as_u64().unwrap() as usize;
+        assert_eq!(json["blocks"][row]["lines"][0][1], "align");
+        assert_eq!(round_trip(&block), block);
+        assert_eq!(round_trip(&block).to_string(), block.to_string());
+    }
+
+    #[test]
+    fn keeps_the_language_escaper() {
+        let block = File::parse("@language json\nmain = \"${v}\"")
+            .unwrap()
+            .template("main");
+        let block = round_trip(&block);
+
+        assert!(block.lines().next().unwrap().source().is_some());
+        assert_eq!(block.set("v", "\"").to_string(), r#""\"""#);
+    }
+
+    #[test]
+    fn keeps_symbols_and_slots_that_appear_more_than_once_the_same() {
+        let symbol = Symbol::new("x");
+        let slot = Slot::new("body");
+        slot.fill("return x;");
+        let block = Block::new(vec![
+            Line(vec![
+                LineSegment::Symbol(Symbol::new("x")),
+                LineSegment::from(" = 1;"),
+            ]),
+            Line(vec![
+                LineSegment::Symbol(symbol.clone()),
+                LineSegment::from(" = 2;"),
+            ]),
+            Line(vec![LineSegment::Symbol(symbol), LineSegment::from(";")]),
+            nested(&slot),
+            nested(&slot),
+        ]);
+
+        let json = serde_json::to_value(&block).unwrap();
+        assert_eq!(
+            json["lines"][2][0],
+            json!({"symbol": {"id": 1, "base": "x"}})
+        );
+        // The value of the slot is written first, as the blocks before it refer to it
+        assert_eq!(
+            json["blocks"][0],
+            json!({"lines": [[{"content": "return x;"}]], "trusted": false})
+        );
+        assert_eq!(
+            json["blocks"][1]["lines"][0][0],
+            json!({"slot": {"id": 0, "name": "body", "value": 0}})
+        );
+        assert_eq!(
+            json["blocks"][2]["lines"][0][0],
+            json!({"slot": {"id": 0, "name": "body"}})
+        );
+
+        let back = round_trip(&block);
+        assert_eq!(serde_json::to_value(&back).unwrap(), json);
+        assert_eq!(
+            Renderer::new().render(&back),
+            "x_1 = 1;\nx_2 = 2;\nx_2;\nreturn x;\nreturn x;"
+        );
+        assert_eq!(
+            Renderer::new().render(&back),
+            Renderer::new().render(&block)
+        );
+    }
+
+    #[test]
+    fn writes_nested_blocks_in_a_flat_table() {
+        let shared = Block::from("shared");
+        let mut block = Block::from("x");
+        for _ in 0..100_000 {
+            block = Block::join(vec![block, shared.clone()]);
+        }
+        let json = serde_json::to_string(&block).unwrap();
+        // Blocks written more than once are only in the table once
+        assert_eq!(json.matches("shared").count(), 1);
+
+        let back: Block = serde_json::from_str(&json).unwrap();
+        assert_eq!(back.to_string(), block.to_string());
+        assert_eq!(serde_json::to_string(&back).unwrap(), json);
+    }
+
+    #[test]
+    fn round_trips_nested_templates() {
+        let file = File::parse("item = - ${name}\nlist =\n    ${items}\n----").unwrap();
+        let mut block = file.template("item").set("name", "leaf");
+        for i in 0..50 {
+            let items = Block::join(vec![
+                file.template("item").set("name", i.to_string()),
+                block,
+            ]);
+            block = file.template("list").set("items", items);
+        }
+        let back = round_trip(&block);
+
+        assert_eq!(back, block);
+        assert_eq!(back.to_string(), block.to_string());
+    }
+
+    #[test]
+    fn fails_to_write_slots_filled_with_themselves() {
+        let slot = Slot::new("s");
+        slot.fill(Block::join(vec![Block::from("x"), Block::from(&slot)]));
+        let block = Block::join(vec![Block::from(&slot)]);
+
+        assert!(serde_json::to_string(&block).is_err());
+    }
+
+    #[test]
+    fn round_trips_parsed_template_files() {
+        let content = "@language rust\nmain =\n  ${?x}let ${x:pascal} = ${|}\"${y}\";\n--+\n";
+        let file = File::parse(content).unwrap().with_path("main.jens");
+        let json = serde_json::to_string_pretty(&file).unwrap();
+        let back: File = serde_json::from_str(&json).unwrap();
+
+        assert_eq!(back.templates, file.templates);
+        assert_eq!(back.template("main"), file.template("main"));
+        insta::assert_snapshot_matches!("serialize.template_file", json);
+    }
+}
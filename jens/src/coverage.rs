@@ -0,0 +1,340 @@
+use crate::{block::Source, escape::Escaper, escape::Language, parser::template::Template};
+use std::{
+    collections::BTreeMap,
+    fmt,
+    sync::{Arc, Mutex},
+};
+
+/// Identifies a template by its file (if known) and name.
+type TemplateKey = (Option<String>, String);
+
+/// Records how the templates of a `File` are used over a generation run: which are
+/// instantiated, which of their placeholders are filled, and which of their lines
+/// reach the output.
+///
+/// Coverage is opt-in. Templates are registered with `File::with_coverage`, after
+/// which the blocks created by `File::template` record the placeholders filled with
+/// `Block::set`. Lines are only recorded when rendered by a `Renderer` given the
+/// coverage with `Renderer::coverage`, which also records placeholders filled by
+/// its resolver.
+///
+/// A `Coverage` is a handle, and its clones all record to the same place.
+#[derive(Clone, Default, Debug)]
+pub struct Coverage(Arc<Mutex<BTreeMap<TemplateKey, TemplateCoverage>>>);
+
+impl Coverage {
+    pub fn new() -> Self {
+        Coverage::default()
+    }
+
+    /// Add a template to the report, so that it's included even if it's never used.
+    pub(crate) fn register(&self, template: &Template) {
+        let mut templates = self.0.lock().unwrap();
+        let key = (template.file.clone(), template.name.clone());
+        let coverage = templates
+            .entry(key)
+            .or_insert_with(|| TemplateCoverage::new(template.file.as_deref(), &template.name));
+        for name in template.placeholder_names() {
+            coverage.placeholders.entry(name).or_insert(0);
+        }
+        for line in &template.lines {
+            coverage.lines.entry(line.line).or_insert(0);
+        }
+    }
+
+    /// Remove a template registered under an earlier `file`, unless something was
+    /// already recorded for it.
+    pub(crate) fn unregister(&self, file: Option<&str>, name: &str) {
+        let mut templates = self.0.lock().unwrap();
+        let key = (file.map(String::from), name.to_owned());
+        if templates.get(&key).is_some_and(TemplateCoverage::is_unused) {
+            templates.remove(&key);
+        }
+    }
+
+    pub(crate) fn record_instantiation(&self, template: &Template) {
+        self.update(template.file.as_deref(), &template.name, |coverage| {
+            coverage.instantiations += 1
+        });
+    }
+
+    pub(crate) fn record_fill(&self, source: &Source, placeholder_name: &str) {
+        self.update(source.file.as_deref(), &source.template, |coverage| {
+            *coverage
+                .placeholders
+                .entry(placeholder_name.into())
+                .or_insert(0) += 1
+        });
+    }
+
+    pub(crate) fn record_line(&self, source: &Source) {
+        self.update(source.file.as_deref(), &source.template, |coverage| {
+            *coverage.lines.entry(source.line).or_insert(0) += 1
+        });
+    }
+
+    fn update<F: FnOnce(&mut TemplateCoverage)>(&self, file: Option<&str>, name: &str, f: F) {
+        let mut templates = self.0.lock().unwrap();
+        let key = (file.map(String::from), name.to_owned());
+        f(templates
+            .entry(key)
+            .or_insert_with(|| TemplateCoverage::new(file, name)));
+    }
+
+    /// What has been recorded so far, with templates sorted by file and name.
+    pub fn report(&self) -> CoverageReport {
+        CoverageReport {
+            templates: self.0.lock().unwrap().values().cloned().collect(),
+        }
+    }
+}
+
+/// How one template was used.
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct TemplateCoverage {
+    pub name: String,
+    pub file: Option<String>,
+
+    /// The number of blocks created from the template by `File::template`.
+    pub instantiations: usize,
+
+    /// The number of times each placeholder was filled, by name.
+    pub placeholders: BTreeMap<String, usize>,
+
+    /// The number of times each line was written, by the line number in the file.
+    pub lines: BTreeMap<usize, usize>,
+}
+
+impl TemplateCoverage {
+    fn new(file: Option<&str>, name: &str) -> Self {
+        TemplateCoverage {
+            name: name.into(),
+            file: file.map(String::from),
+            instantiations: 0,
+            placeholders: BTreeMap::new(),
+            lines: BTreeMap::new(),
+        }
+    }
+
+    fn is_unused(&self) -> bool {
+        self.instantiations == 0
+            && self.placeholders.values().all(|&count| count == 0)
+            && self.lines.values().all(|&count| count == 0)
+    }
+
+    /// The placeholders that were never filled.
+    pub fn unfilled_placeholders(&self) -> Vec<&str> {
+        let unfilled = self.placeholders.iter().filter(|(_, &count)| count == 0);
+        unfilled.map(|(name, _)| name.as_str()).collect()
+    }
+
+    /// The lines that never reached the output.
+    pub fn unwritten_lines(&self) -> Vec<usize> {
+        let unwritten = self.lines.iter().filter(|(_, &count)| count == 0);
+        unwritten.map(|(&line, _)| line).collect()
+    }
+}
+
+/// A snapshot of a `Coverage`, which can be written out as text (with `Display`) or
+/// as JSON.
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct CoverageReport {
+    pub templates: Vec<TemplateCoverage>,
+}
+
+impl CoverageReport {
+    /// The templates that were never instantiated.
+    pub fn unused_templates(&self) -> Vec<&TemplateCoverage> {
+        let unused = self.templates.iter().filter(|t| t.instantiations == 0);
+        unused.collect()
+    }
+
+    /// The report as JSON, with an object for each template:
+    ///
+    /// ```json
+    /// {"templates": [
+    ///   {"name": "field", "file": "types.jens", "instantiations": 2,
+    ///    "placeholders": {"name": 2, "type": 0}, "lines": {"6": 2}}
+    /// ]}
+    /// ```
+    pub fn to_json(&self) -> String {
+        let string = |s: &str| format!("\"{}\"", Language::Json.escape(s));
+        let templates: Vec<_> = self
+            .templates
+            .iter()
+            .map(|t| {
+                let placeholders: Vec<_> = t
+                    .placeholders
+                    .iter()
+                    .map(|(name, count)| format!("{}: {}", string(name), count))
+                    .collect();
+                let lines: Vec<_> = t
+                    .lines
+                    .iter()
+                    .map(|(line, count)| format!("\"{}\": {}", line, count))
+                    .collect();
+                format!(
+                    "  {{\"name\": {}, \"file\": {}, \"instantiations\": {}, \"placeholders\": {{{}}}, \"lines\": {{{}}}}}",
+                    string(&t.name),
+                    t.file.as_deref().map_or("null".into(), string),
+                    t.instantiations,
+                    placeholders.join(", "),
+                    lines.join(", ")
+                )
+            })
+            .collect();
+        if templates.is_empty() {
+            return "{\"templates\": []}".into();
+        }
+        format!("{{\"templates\": [\n{}\n]}}", templates.join(",\n"))
+    }
+}
+
+/// Lists each template with how often it was instantiated, followed by anything in
+/// it that was never used.
+impl fmt::Display for CoverageReport {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for t in &self.templates {
+            match &t.file {
+                Some(file) => write!(f, "{} ({})", t.name, file)?,
+                None => write!(f, "{}", t.name)?,
+            }
+            writeln!(f, ": instantiated {} times", t.instantiations)?;
+            let unfilled = t.unfilled_placeholders();
+            if !unfilled.is_empty() {
+                writeln!(f, "  unfilled placeholders: {}", unfilled.join(", "))?;
+            }
+            let unwritten: Vec<_> = t.unwritten_lines().iter().map(usize::to_string).collect();
+            if !unwritten.is_empty() {
+                writeln!(f, "  unwritten lines: {}", unwritten.join(", "))?;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{Block, Coverage, File, Renderer, Scope};
+
+    const TEMPLATES: &str = "struct =
+    ${?derive}#[derive(${derive})]
+    struct ${name} {
+        ${fields}
+    }
+----
+field =
+    ${name}: ${type},
+----
+unused =
+    ${x}
+----
+";
+
+    fn file(coverage: &Coverage) -> File {
+        File::parse(TEMPLATES)
+            .unwrap()
+            .with_path("types.jens")
+            .with_coverage(coverage)
+    }
+
+    #[test]
+    fn records_templates_placeholders_and_lines_used() {
+        let coverage = Coverage::new();
+        let f = file(&coverage);
+        let fields = Block::join(
+            ["a", "b"]
+                .iter()
+                .map(|&n| f.template("field").set("name", n)),
+        );
+        let block = f.template("struct").set("name", "A").set("fields", fields);
+        let out = Renderer::new().coverage(&coverage).render(&block);
+        assert_eq!(out, "struct A {\n    a: ${type},\n    b: ${type},\n}");
+
+        let report = coverage.report();
+        let names: Vec<_> = report.unused_templates().iter().map(|t| &t.name).collect();
+        assert_eq!(names, ["unused"]);
+        assert_eq!(
+            report.to_string(),
+            "field (types.jens): instantiated 2 times
+  unfilled placeholders: type
+struct (types.jens): instantiated 1 times
+  unfilled placeholders: derive
+  unwritten lines: 2
+unused (types.jens): instantiated 0 times
+  unfilled placeholders: x
+  unwritten lines: 11
+"
+        );
+        assert_eq!(
+            report.to_json(),
+            r#"{"templates": [
+  {"name": "field", "file": "types.jens", "instantiations": 2, "placeholders": {"name": 2, "type": 0}, "lines": {"8": 2}},
+  {"name": "struct", "file": "types.jens", "instantiations": 1, "placeholders": {"derive": 0, "fields": 1, "name": 1}, "lines": {"2": 0, "3": 1, "4": 1, "5": 1}},
+  {"name": "unused", "file": "types.jens", "instantiations": 0, "placeholders": {"x": 0}, "lines": {"11": 0}}
+]}"#
+        );
+    }
+
+    #[test]
+    fn records_placeholders_filled_by_the_resolver() {
+        let coverage = Coverage::new();
+        let f = file(&coverage);
+        let scope = Scope::new().set("type", "u8");
+        let block = f.template("field").set("name", "a");
+        let renderer = Renderer::new().resolver(&scope).coverage(&coverage);
+        assert_eq!(renderer.render(&block), "a: u8,");
+
+        let report = coverage.report();
+        let field = &report.templates[0];
+        assert_eq!(field.unfilled_placeholders(), Vec::<&str>::new());
+        assert_eq!(field.lines[&8], 1);
+    }
+
+    #[test]
+    fn only_records_when_opted_in() {
+        let coverage = Coverage::new();
+        let f = File::parse(TEMPLATES).unwrap();
+        let block = f.template("field").set("name", "a");
+        Renderer::new().coverage(&coverage).render(&block);
+        assert_eq!(
+            coverage.report().to_json(),
+            r#"{"templates": [
+  {"name": "field", "file": null, "instantiations": 0, "placeholders": {}, "lines": {"8": 1}}
+]}"#
+        );
+
+        let coverage = Coverage::new();
+        let f = file(&coverage);
+        f.template("field").set("name", "a").to_string();
+        let field = &coverage.report().templates[0];
+        assert_eq!((field.instantiations, field.placeholders["name"]), (1, 1));
+        assert_eq!(field.unwritten_lines(), [8]);
+    }
+
+    #[test]
+    fn registers_templates_under_paths_given_after_coverage() {
+        let coverage = Coverage::new();
+        let f = File::parse(TEMPLATES)
+            .unwrap()
+            .with_coverage(&coverage)
+            .with_path("types.jens");
+        f.template("field").set("name", "a").to_string();
+
+        let report = coverage.report();
+        let templates: Vec<_> = report
+            .templates
+            .iter()
+            .map(|t| (t.file.as_deref(), t.name.as_str()))
+            .collect();
+        assert_eq!(
+            templates,
+            [
+                (Some("types.jens"), "field"),
+                (Some("types.jens"), "struct"),
+                (Some("types.jens"), "unused"),
+            ]
+        );
+        assert_eq!(report.templates[0].placeholders["name"], 1);
+    }
+}
@@ -0,0 +1,179 @@
+use std::borrow::Cow;
+
+/// Escapes text values (blocks converted from strings) as they are inserted into a
+/// template with `Block::set`, so that they can't break out of the surrounding code.
+///
+/// Text values filled in by a `Renderer`'s resolver are escaped too, by the escaper
+/// of the block the placeholder is in, or else of the nearest block around it that
+/// has one.
+pub trait Escaper: Send + Sync {
+    fn escape<'a>(&self, text: &'a str) -> Cow<'a, str>;
+
+    /// The built-in language this escapes text for, if it is one. Blocks are only
+    /// serialized along with their escaper if it is.
+    fn language(&self) -> Option<Language> {
+        None
+    }
+}
+
+impl<F> Escaper for F
+where
+    F: Fn(&str) -> String + Send + Sync,
+{
+    fn escape<'a>(&self, text: &'a str) -> Cow<'a, str> {
+        Cow::Owned(self(text))
+    }
+}
+
+/// The built-in escapers, for the target languages a template file can declare with
+/// `@language name` (or a single template with `name:language =`).
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(rename_all = "lowercase"))]
+pub enum Language {
+    /// The contents of a Rust string literal.
+    Rust,
+
+    /// The contents of a JSON string.
+    Json,
+
+    /// The contents of a JavaScript or TypeScript string (with any kind of quotes,
+    /// including template literals), which is also safe inside a `<script>` element.
+    JavaScript,
+
+    /// HTML text or attribute values.
+    Html,
+}
+
+impl Language {
+    /// Look up a language by the name used to declare it in a template file.
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "rust" => Some(Language::Rust),
+            "json" => Some(Language::Json),
+            "javascript" | "typescript" => Some(Language::JavaScript),
+            "html" => Some(Language::Html),
+            _ => None,
+        }
+    }
+}
+
+impl Escaper for Language {
+    fn language(&self) -> Option<Language> {
+        Some(*self)
+    }
+
+    fn escape<'a>(&self, text: &'a str) -> Cow<'a, str> {
+        match self {
+            Language::Rust => escape_chars(text, |c| match c {
+                '"' => Some("\\\"".into()),
+                '\\' => Some("\\\\".into()),
+                '\n' => Some("\\n".into()),
+                '\r' => Some("\\r".into()),
+                '\t' => Some("\\t".into()),
+                '\0' => Some("\\0".into()),
+                c if c.is_control() => Some(format!("\\u{{{:x}}}", c as u32).into()),
+                _ => None,
+            }),
+            Language::Json => escape_chars(text, escape_json),
+            Language::JavaScript => escape_chars(text, |c| match c {
+                '\'' => Some("\\'".into()),
+                '`' => Some("\\`".into()),
+                '$' => Some("\\$".into()),
+                '<' => Some("\\u003C".into()),
+                '\u{2028}' => Some("\\u2028".into()),
+                '\u{2029}' => Some("\\u2029".into()),
+                c => escape_json(c),
+            }),
+            Language::Html => escape_chars(text, |c| match c {
+                '&' => Some("&amp;".into()),
+                '<' => Some("&lt;".into()),
+                '>' => Some("&gt;".into()),
+                '"' => Some("&quot;".into()),
+                '\'' => Some("&#39;".into()),
+                _ => None,
+            }),
+        }
+    }
+}
+
+fn escape_json(c: char) -> Option<Cow<'static, str>> {
+    match c {
+        '"' => Some("\\\"".into()),
+        '\\' => Some("\\\\".into()),
+        '\n' => Some("\\n".into()),
+        '\r' => Some("\\r".into()),
+        '\t' => Some("\\t".into()),
+        '\u{8}' => Some("\\b".into()),
+        '\u{c}' => Some("\\f".into()),
+        c if (c as u32) < 0x20 => Some(format!("\\u{:04x}", c as u32).into()),
+        _ => None,
+    }
+}
+
+/// Replace the characters of `text` for which `escape` returns a replacement,
+/// without copying the text if there are none.
+fn escape_chars<F>(text: &str, escape: F) -> Cow<'_, str>
+where
+    F: Fn(char) -> Option<Cow<'static, str>>,
+{
+    let mut escaped = String::new();
+    let mut unescaped = 0;
+    for (i, c) in text.char_indices() {
+        if let Some(replacement) = escape(c) {
+            escaped.push_str(&text[unescaped..i]);
+            escaped.push_str(&replacement);
+            unescaped = i + c.len_utf8();
+        }
+    }
+    if unescaped == 0 {
+        return Cow::Borrowed(text);
+    }
+    escaped.push_str(&text[unescaped..]);
+    Cow::Owned(escaped)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn escapes_rust_strings() {
+        assert_eq!(Language::Rust.escape("plain"), Cow::Borrowed("plain"));
+        assert_eq!(
+            Language::Rust.escape("say \"hi\"\\\n\u{1b}"),
+            "say \\\"hi\\\"\\\\\\n\\u{1b}"
+        );
+    }
+
+    #[test]
+    fn escapes_json_strings() {
+        assert_eq!(
+            Language::Json.escape("a\"b\\c\nd\u{1}"),
+            "a\\\"b\\\\c\\nd\\u0001"
+        );
+        assert_eq!(Language::Json.escape("it's </script>"), "it's </script>");
+    }
+
+    #[test]
+    fn escapes_javascript_strings() {
+        assert_eq!(
+            Language::JavaScript.escape("it's `${x}` </script>"),
+            "it\\'s \\`\\${x}\\` \\u003C/script>"
+        );
+    }
+
+    #[test]
+    fn escapes_html() {
+        assert_eq!(
+            Language::Html.escape("<a href=\"x\">Tom & Jerry's</a>"),
+            "&lt;a href=&quot;x&quot;&gt;Tom &amp; Jerry&#39;s&lt;/a&gt;"
+        );
+    }
+
+    #[test]
+    fn uses_closures_as_escapers() {
+        let upper = |s: &str| s.to_uppercase();
+        assert_eq!(upper.escape("abc"), "ABC");
+    }
+}
@@ -1,26 +1,86 @@
 use crate::{
     block::Block,
+    coverage::Coverage,
     parser::{self, template::Template},
 };
 
+/// A parsed template file.
+///
+/// With the `serde` feature, files can be serialized and deserialized. In JSON, a
+/// file is an object with its `templates`, each of which has a `name`, a `file`
+/// (see `with_path`) and `language` (or `null`), the width of indentation ignored
+/// at the start of each line (`indent_ignored`), and its `lines`. A line has its
+/// `indentation`, its `segments` (as in a `Block`, except that conditions hold
+/// just the placeholder name) and the `line` of the file it's on:
+///
+/// ```json
+/// {"templates": [{
+///   "name": "field", "file": "types.jens", "language": "rust", "indent_ignored": 2,
+///   "lines": [{
+///     "indentation": "  ",
+///     "segments": [{"placeholder": {"name": "name"}}, {"content": ": "}, "align"],
+///     "line": 2
+///   }]
+/// }]}
+/// ```
+///
+/// Coverage (see `with_coverage`) isn't serialized.
 #[derive(Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct File {
     pub templates: Vec<Template>,
+    #[cfg_attr(feature = "serde", serde(skip))]
+    coverage: Option<Coverage>,
 }
 
 impl File {
     // TODO: Add custom parse error
+    #[allow(clippy::result_unit_err)]
     pub fn parse(content: &str) -> Result<Self, ()> {
         parser::parse(content)
-            .map(|templates| File { templates })
+            .map(|templates| File {
+                templates,
+                coverage: None,
+            })
             .map_err(|_| ())
     }
 
+    /// Record the path the templates were loaded from, so that the lines of the
+    /// blocks created from them refer to it (see `Source`).
+    pub fn with_path(mut self, path: &str) -> Self {
+        for template in &mut self.templates {
+            let file = template.file.replace(path.into());
+            // Registered again under the new path, if coverage was already set
+            if let Some(coverage) = &self.coverage {
+                coverage.unregister(file.as_deref(), &template.name);
+                coverage.register(template);
+            }
+        }
+        self
+    }
+
+    /// Record how the templates are used to `coverage`: the blocks created from
+    /// them, the placeholders set in those blocks, and (when rendered with
+    /// `Renderer::coverage`) the lines written.
+    pub fn with_coverage(mut self, coverage: &Coverage) -> Self {
+        for template in &self.templates {
+            coverage.register(template);
+        }
+        self.coverage = Some(coverage.clone());
+        self
+    }
+
     /// Find a template in the template definition file.
     pub fn template_opt(&self, template_name: &str) -> Option<Block> {
         for t in &self.templates {
             if t.name == template_name {
-                return Some(t.into());
+                return Some(match &self.coverage {
+                    Some(coverage) => {
+                        coverage.record_instantiation(t);
+                        Block::from(t).with_coverage(coverage)
+                    }
+                    None => t.into(),
+                });
             }
         }
         None
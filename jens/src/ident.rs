@@ -0,0 +1,304 @@
+//! Helpers for turning names into identifiers of a particular style.
+
+use crate::block::{Block, Line, LineSegment};
+use std::{
+    borrow::Cow,
+    sync::atomic::{AtomicUsize, Ordering},
+};
+
+/// A naming convention for identifiers, which can also be applied to a placeholder
+/// in a template with `${name:case}`.
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(rename_all = "lowercase"))]
+pub enum Case {
+    /// `snake_case`
+    Snake,
+
+    /// `camelCase`
+    Camel,
+
+    /// `PascalCase`
+    Pascal,
+
+    /// `SCREAMING_SNAKE_CASE`
+    Screaming,
+
+    /// `kebab-case`
+    Kebab,
+}
+
+impl Case {
+    /// Look up a case by the name used for it in templates.
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "snake" => Some(Case::Snake),
+            "camel" => Some(Case::Camel),
+            "pascal" => Some(Case::Pascal),
+            "screaming" => Some(Case::Screaming),
+            "kebab" => Some(Case::Kebab),
+            _ => None,
+        }
+    }
+
+    /// Convert `name` to this case.
+    pub fn apply(self, name: &str) -> String {
+        let words = words(name);
+        match self {
+            Case::Snake => join(&words, "_", str::to_lowercase),
+            Case::Camel => {
+                let mut out = String::new();
+                for (i, word) in words.iter().enumerate() {
+                    if i == 0 {
+                        out.push_str(&word.to_lowercase());
+                    } else {
+                        out.push_str(&capitalize(word));
+                    }
+                }
+                out
+            }
+            Case::Pascal => join(&words, "", capitalize),
+            Case::Screaming => join(&words, "_", str::to_uppercase),
+            Case::Kebab => join(&words, "-", str::to_lowercase),
+        }
+    }
+}
+
+fn join(words: &[&str], separator: &str, convert: fn(&str) -> String) -> String {
+    let words: Vec<String> = words.iter().map(|word| convert(word)).collect();
+    words.join(separator)
+}
+
+fn capitalize(word: &str) -> String {
+    let mut chars = word.chars();
+    match chars.next() {
+        Some(first) => first
+            .to_uppercase()
+            .chain(chars.flat_map(char::to_lowercase))
+            .collect(),
+        None => String::new(),
+    }
+}
+
+/// Split a name in any convention into its words.
+///
+/// Words are separated by anything that isn't a letter or digit, and by changes of
+/// case, keeping acronyms together (`XMLHttpRequest` is `XML`, `Http`, `Request`).
+pub fn words(name: &str) -> Vec<&str> {
+    let chars: Vec<(usize, char)> = name.char_indices().collect();
+    let mut words = Vec::new();
+    let mut start = None;
+    for (i, &(index, c)) in chars.iter().enumerate() {
+        if !c.is_alphanumeric() {
+            if let Some(start) = start.take() {
+                words.push(&name[start..index]);
+            }
+            continue;
+        }
+        let prev = i.checked_sub(1).map(|i| chars[i].1);
+        let next = chars.get(i + 1).map(|&(_, c)| c);
+        let boundary = c.is_uppercase()
+            && match prev {
+                Some(prev) if prev.is_lowercase() || prev.is_numeric() => true,
+                Some(prev) if prev.is_uppercase() => next.is_some_and(char::is_lowercase),
+                _ => false,
+            };
+        match start {
+            Some(word_start) if boundary => {
+                words.push(&name[word_start..index]);
+                start = Some(index);
+            }
+            None => start = Some(index),
+            _ => {}
+        }
+    }
+    if let Some(start) = start {
+        words.push(&name[start..]);
+    }
+    words
+}
+
+/// The keywords (including reserved words) of Rust.
+pub const RUST_KEYWORDS: &[&str] = &[
+    "abstract", "as", "async", "await", "become", "box", "break", "const", "continue", "crate",
+    "do", "dyn", "else", "enum", "extern", "false", "final", "fn", "for", "gen", "if", "impl",
+    "in", "let", "loop", "macro", "match", "mod", "move", "mut", "override", "priv", "pub", "ref",
+    "return", "self", "Self", "static", "struct", "super", "trait", "true", "try", "type",
+    "typeof", "unsafe", "unsized", "use", "virtual", "where", "while", "yield",
+];
+
+/// The reserved words of JavaScript and TypeScript.
+pub const JAVASCRIPT_KEYWORDS: &[&str] = &[
+    "await",
+    "break",
+    "case",
+    "catch",
+    "class",
+    "const",
+    "continue",
+    "debugger",
+    "default",
+    "delete",
+    "do",
+    "else",
+    "enum",
+    "export",
+    "extends",
+    "false",
+    "finally",
+    "for",
+    "function",
+    "if",
+    "implements",
+    "import",
+    "in",
+    "instanceof",
+    "interface",
+    "let",
+    "new",
+    "null",
+    "package",
+    "private",
+    "protected",
+    "public",
+    "return",
+    "static",
+    "super",
+    "switch",
+    "this",
+    "throw",
+    "true",
+    "try",
+    "typeof",
+    "var",
+    "void",
+    "while",
+    "with",
+    "yield",
+];
+
+/// Make `name` a valid identifier that isn't one of `keywords`.
+///
+/// Characters other than letters, digits and underscores are replaced with
+/// underscores, names starting with a digit are prefixed with one, and keywords
+/// have one appended (`type` becomes `type_`).
+pub fn sanitize(name: &str, keywords: &[&str]) -> String {
+    let mut ident: String = name
+        .chars()
+        .map(|c| if c.is_alphanumeric() { c } else { '_' })
+        .collect();
+    if ident.is_empty() || ident.starts_with(|c: char| c.is_numeric()) {
+        ident.insert(0, '_');
+    }
+    if keywords.contains(&ident.as_str()) {
+        ident.push('_');
+    }
+    ident
+}
+
+/// Make `name` a valid Rust identifier, using a raw identifier for keywords (`type`
+/// becomes `r#type`). The few keywords which can't be raw identifiers have an
+/// underscore appended instead.
+pub fn rust_ident(name: &str) -> String {
+    let ident = sanitize(name, &[]);
+    match ident.as_str() {
+        "crate" | "self" | "Self" | "super" | "_" => ident + "_",
+        keyword if RUST_KEYWORDS.contains(&keyword) => format!("r#{}", ident),
+        _ => ident,
+    }
+}
+
+/// A fresh identifier, named when it's rendered by numbering its base name (`tmp_1`,
+/// `tmp_2`, ...) in the order the symbols in a scope are first written. The names
+/// only depend on the output, so they are the same every time it's rendered.
+///
+/// Every clone of a symbol is written with the same name. A block becomes a scope
+/// with `Block::symbol_scope`, and the block being rendered is always one. Symbols
+/// are named in the innermost scope they are first written in, after any names
+/// already used in the scopes around it, so sibling scopes can reuse names.
+#[derive(Clone, PartialEq, Eq, Hash, Debug)]
+pub struct Symbol {
+    id: usize,
+    base: Cow<'static, str>,
+}
+
+impl Symbol {
+    pub fn new<T: Into<Cow<'static, str>>>(base: T) -> Self {
+        static NEXT_ID: AtomicUsize = AtomicUsize::new(0);
+        Symbol {
+            id: NEXT_ID.fetch_add(1, Ordering::Relaxed),
+            base: base.into(),
+        }
+    }
+
+    /// Identifies this symbol (and its clones) while rendering.
+    pub(crate) fn id(&self) -> usize {
+        self.id
+    }
+
+    /// The name this symbol is numbered from.
+    pub fn base(&self) -> &str {
+        &self.base
+    }
+}
+
+impl From<Symbol> for Block {
+    fn from(symbol: Symbol) -> Self {
+        Block::new(vec![Line(vec![LineSegment::Symbol(symbol)])])
+    }
+}
+
+impl<'a> From<&'a Symbol> for Block {
+    fn from(symbol: &'a Symbol) -> Self {
+        Block::from(symbol.clone())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn splits_words() {
+        assert_eq!(words("already_snake"), vec!["already", "snake"]);
+        assert_eq!(words("XMLHttpRequest"), vec!["XML", "Http", "Request"]);
+        assert_eq!(words("myHTTP2Server"), vec!["my", "HTTP2", "Server"]);
+        assert_eq!(
+            words("  kebab-case  value "),
+            vec!["kebab", "case", "value"]
+        );
+        assert_eq!(words("über_größe"), vec!["über", "größe"]);
+        assert!(words("--").is_empty());
+    }
+
+    #[test]
+    fn converts_cases() {
+        let name = "user_id list";
+        assert_eq!(Case::Snake.apply(name), "user_id_list");
+        assert_eq!(Case::Camel.apply(name), "userIdList");
+        assert_eq!(Case::Pascal.apply(name), "UserIdList");
+        assert_eq!(Case::Screaming.apply(name), "USER_ID_LIST");
+        assert_eq!(Case::Kebab.apply(name), "user-id-list");
+
+        assert_eq!(Case::Snake.apply("HTTPServer"), "http_server");
+        assert_eq!(Case::Pascal.apply("HTTP_SERVER"), "HttpServer");
+        assert_eq!(Case::Camel.apply("ÉtatCivil"), "étatCivil");
+    }
+
+    #[test]
+    fn sanitizes_identifiers() {
+        assert_eq!(sanitize("type", RUST_KEYWORDS), "type_");
+        assert_eq!(sanitize("type", JAVASCRIPT_KEYWORDS), "type");
+        assert_eq!(sanitize("class", JAVASCRIPT_KEYWORDS), "class_");
+        assert_eq!(sanitize("2nd-field", JAVASCRIPT_KEYWORDS), "_2nd_field");
+        assert_eq!(sanitize("", &[]), "_");
+    }
+
+    #[test]
+    fn makes_rust_identifiers() {
+        assert_eq!(rust_ident("type"), "r#type");
+        assert_eq!(rust_ident("self"), "self_");
+        assert_eq!(rust_ident("field name"), "field_name");
+        assert_eq!(rust_ident("Self"), "Self_");
+    }
+}
@@ -1,6 +1,18 @@
-pub use block::{Block, IteratorLocation, Line, LineSegment};
+pub use block::{
+    Block, Condition, Group, Indent, IteratorLocation, Line, LineSegment, Placeholder, Position,
+    Slot, Source, Wrapped,
+};
+pub use coverage::{Coverage, CoverageReport, TemplateCoverage};
+pub use escape::{Escaper, Language};
 pub use file::File;
+pub use render::{RenderError, Renderer, SourceMap};
+pub use resolver::{Chain, Resolver, Scope};
 
 mod block;
+mod coverage;
+mod escape;
 mod file;
+pub mod ident;
 mod parser;
+mod render;
+mod resolver;
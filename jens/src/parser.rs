@@ -1,6 +1,8 @@
 use self::{segment::Segment, template::Template};
+use crate::escape::Language;
 use pest::{error::Error as PestError, iterators::Pair, Parser};
 use pest_derive::Parser;
+use std::convert::TryFrom;
 
 pub(crate) mod segment;
 pub(crate) mod template;
@@ -11,13 +13,22 @@ struct GrammarParser;
 
 pub(crate) fn parse(content: &str) -> Result<Vec<Template>, PestError<Rule>> {
     GrammarParser::parse(Rule::file, content)
-        .and_then(|mut pairs| Ok(pairs.next().unwrap()))
+        .map(|mut pairs| pairs.next().unwrap())
         .and_then(|pair| {
             let mut templates: Vec<Template> = vec![];
+            // Set by `@language`, for the templates which follow it
+            let mut language = None;
             for item in pair.into_inner() {
                 match item.as_rule() {
-                    Rule::template => templates.push(Template::from(item)),
-                    Rule::template_one_liner => templates.push(Template::from(item)),
+                    Rule::language_decl => {
+                        let name = item.into_inner().next().unwrap().as_str();
+                        language = Language::from_name(name);
+                    }
+                    Rule::template | Rule::template_one_liner => {
+                        let mut template = Template::try_from(item)?;
+                        template.language = template.language.or(language);
+                        templates.push(template);
+                    }
                     Rule::EOI => {}
                     unknown => panic!("Unexpected rule '{:?}' found", unknown),
                 }
@@ -30,9 +41,9 @@ pub(crate) fn parse(content: &str) -> Result<Vec<Template>, PestError<Rule>> {
 // Attempt to make the parsing single phase, otherwise clean up this function.
 pub(crate) fn parse_phase2(content: &str) -> Result<Vec<Segment>, PestError<Rule>> {
     GrammarParser::parse(Rule::template_phase2, content)
-        .and_then(|mut pairs| Ok(pairs.next().unwrap()))
-        .and_then(|pairs| {
-            Ok(pairs
+        .map(|mut pairs| pairs.next().unwrap())
+        .map(|pairs| {
+            pairs
                 .into_inner()
                 .filter_map(|pair| {
                     if Rule::EOI == pair.as_rule() {
@@ -40,12 +51,12 @@ pub(crate) fn parse_phase2(content: &str) -> Result<Vec<Segment>, PestError<Rule
                     }
                     Some(Segment::from(pair))
                 })
-                .collect())
+                .collect()
         })
 }
 
 pub(crate) fn get_ident(pair: Pair<'_, Rule>) -> String {
-    pair.into_inner().nth(0).unwrap().as_str().into()
+    pair.into_inner().next().unwrap().as_str().into()
 }
 
 #[cfg(test)]
@@ -1,10 +1,28 @@
-use crate::parser::{get_ident, Rule};
+use crate::{
+    block::{Indent, Placeholder},
+    ident::Case,
+    parser::{get_ident, Rule},
+};
 use pest::iterators::Pair;
 
 #[derive(Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(rename_all = "snake_case"))]
 pub enum Segment {
     Content(String),
-    Placeholder(String),
+    Placeholder(Placeholder),
+
+    /// `${?name}`: the line is only kept when `name` is set to something non-empty.
+    /// On a line of its own, this starts a section of lines that are all conditional.
+    Condition(String),
+
+    /// `${/name}`: ends the section started by `${?name}`.
+    SectionEnd(String),
+
+    /// `${|}`: the text after this is aligned with the same column on the lines
+    /// around it.
+    Align,
+    EndOfInput,
 }
 
 impl From<Pair<'_, Rule>> for Segment {
@@ -12,16 +30,44 @@ impl From<Pair<'_, Rule>> for Segment {
         match pair.as_rule() {
             Rule::escaped_dollar => Segment::Content("$".into()),
             Rule::not_placeholder => Segment::Content(pair.as_str().into()),
-            Rule::placeholder => Segment::Placeholder(get_ident(pair)),
+            Rule::placeholder => Segment::Placeholder(pair.into()),
+            Rule::condition => Segment::Condition(get_ident(pair)),
+            Rule::section_end => Segment::SectionEnd(get_ident(pair)),
+            Rule::align => Segment::Align,
             _ => unreachable!(),
         }
     }
 }
 
+impl From<Pair<'_, Rule>> for Placeholder {
+    fn from(pair: Pair<'_, Rule>) -> Self {
+        let mut placeholder = Placeholder::new("");
+        for item in pair.into_inner() {
+            match item.as_rule() {
+                Rule::identifier => placeholder.name = item.as_str().into(),
+                Rule::indent_mode => placeholder.indent = Some(indent_mode(item.as_str())),
+                Rule::case => placeholder.case = Case::from_name(item.as_str()),
+                _ => unreachable!(),
+            }
+        }
+        placeholder
+    }
+}
+
+fn indent_mode(mode: &str) -> Indent {
+    match mode {
+        "column" => Indent::Column,
+        "leading" => Indent::Leading,
+        "hanging" => Indent::Hanging,
+        "none" => Indent::None,
+        _ => unreachable!(),
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
-    use crate::parser::parse;
+    use crate::parser::{parse, parse_phase2};
 
     fn tmpl_line(content: &str) -> String {
         format!("main =\n    {}\n----", content)
@@ -35,6 +81,75 @@ mod tests {
         assert_eq!(segments, &[Segment::Placeholder("x".into())]);
     }
 
+    #[test]
+    fn placeholder_with_indent_mode() {
+        let templates = parse(&tmpl_line("${x:hanging} ${y:none}")).unwrap();
+        let segments = &templates[0].lines[0].segments;
+
+        assert_eq!(
+            segments,
+            &[
+                Segment::Placeholder(Placeholder::new("x").with_indent(Indent::Hanging)),
+                Segment::Content(" ".into()),
+                Segment::Placeholder(Placeholder::new("y").with_indent(Indent::None)),
+            ]
+        );
+    }
+
+    #[test]
+    fn condition() {
+        let templates = parse(&tmpl_line("${?x}pub ${x}")).unwrap();
+        let segments = &templates[0].lines[0].segments;
+
+        assert_eq!(
+            segments,
+            &[
+                Segment::Condition("x".into()),
+                Segment::Content("pub ".into()),
+                Segment::Placeholder("x".into()),
+            ]
+        );
+    }
+
+    #[test]
+    fn section_end() {
+        let segments = parse_phase2("${/x}").unwrap();
+
+        assert_eq!(segments, &[Segment::SectionEnd("x".into())]);
+    }
+
+    #[test]
+    fn align() {
+        let templates = parse(&tmpl_line("${a}:${|} ${b}")).unwrap();
+        let segments = &templates[0].lines[0].segments;
+
+        assert_eq!(
+            segments,
+            &[
+                Segment::Placeholder("a".into()),
+                Segment::Content(":".into()),
+                Segment::Align,
+                Segment::Content(" ".into()),
+                Segment::Placeholder("b".into()),
+            ]
+        );
+    }
+
+    #[test]
+    fn placeholder_with_case() {
+        let templates = parse(&tmpl_line("${x:pascal:hanging}")).unwrap();
+        let segments = &templates[0].lines[0].segments;
+
+        assert_eq!(
+            segments,
+            &[Segment::Placeholder(
+                Placeholder::new("x")
+                    .with_case(Case::Pascal)
+                    .with_indent(Indent::Hanging)
+            )]
+        );
+    }
+
     #[test]
     fn raw_content() {
         let templates = parse(&tmpl_line("content")).unwrap();
@@ -1,57 +1,225 @@
-use crate::parser::{get_ident, parse_phase2, segment::Segment, Rule};
-use pest::iterators::Pair;
+use crate::{
+    block::Source,
+    escape::Language,
+    parser::{parse_phase2, segment::Segment, Rule},
+};
+use pest::{
+    error::{Error as PestError, ErrorVariant},
+    iterators::Pair,
+    Span,
+};
+use std::{
+    borrow::Cow,
+    convert::TryFrom,
+    fmt,
+    sync::{Arc, OnceLock},
+};
 
-#[derive(Debug, Default, PartialEq)]
+#[derive(Default)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct Template {
     pub name: String,
+
+    /// The path of the file the template was parsed from, if known.
+    pub file: Option<String>,
+
+    /// The target language, used to escape text values inserted into the template.
+    pub language: Option<Language>,
     pub indent_ignored: usize,
     pub lines: Vec<TemplateLine>,
+
+    /// The `Source` of each line, shared by the blocks created from the template.
+    #[cfg_attr(feature = "serde", serde(skip))]
+    sources: OnceLock<Vec<Arc<Source>>>,
 }
 
-impl<'a> From<Pair<'a, Rule>> for Template {
-    fn from(pair: Pair<'a, Rule>) -> Template {
+impl fmt::Debug for Template {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Template")
+            .field("name", &self.name)
+            .field("file", &self.file)
+            .field("language", &self.language)
+            .field("indent_ignored", &self.indent_ignored)
+            .field("lines", &self.lines)
+            .finish()
+    }
+}
+
+impl PartialEq for Template {
+    fn eq(&self, other: &Template) -> bool {
+        self.name == other.name
+            && self.file == other.file
+            && self.language == other.language
+            && self.indent_ignored == other.indent_ignored
+            && self.lines == other.lines
+    }
+}
+
+/// Fails if the sections in the template aren't closed properly.
+impl<'a> TryFrom<Pair<'a, Rule>> for Template {
+    type Error = PestError<Rule>;
+
+    fn try_from(pair: Pair<'a, Rule>) -> Result<Template, Self::Error> {
         let mut template = Template::default();
+        // The span of each line, for errors
+        let mut spans = Vec::new();
 
         for item in pair.into_inner() {
             match item.as_rule() {
-                Rule::template_content => template.lines.push(item.as_str().into()),
-                Rule::template_decl => template.name = get_ident(item),
-                Rule::template_line => template.lines.push(item.into()),
+                Rule::template_content => {
+                    let line = item.as_span().start_pos().line_col().0;
+                    spans.push(item.as_span());
+                    template.lines.push(TemplateLine {
+                        line,
+                        ..item.as_str().into()
+                    });
+                    template.apply_sections(&spans)?;
+                }
+                Rule::template_decl => {
+                    for decl in item.into_inner() {
+                        match decl.as_rule() {
+                            Rule::identifier => template.name = decl.as_str().into(),
+                            Rule::language => {
+                                template.language = Language::from_name(decl.as_str())
+                            }
+                            _ => unreachable!(),
+                        }
+                    }
+                }
+                Rule::template_line => {
+                    spans.push(item.as_span());
+                    template.lines.push(item.into());
+                }
                 Rule::template_terminator => {
-                    template.indent_ignored = item.as_str().matches("-").count()
+                    template.apply_sections(&spans)?;
+                    let terminator = item.as_str().trim_end();
+                    template.indent_ignored = terminator.matches('-').count();
+                    // A terminator ending in `+` keeps the newline after the last line
+                    if terminator.ends_with('+') {
+                        if template.lines.is_empty() {
+                            template.lines.push(TemplateLine {
+                                line: item.as_span().start_pos().line_col().0,
+                                ..TemplateLine::default()
+                            });
+                        }
+                        let last_line = template.lines.last_mut().unwrap();
+                        last_line.segments.push(Segment::EndOfInput);
+                    }
+                }
+                Rule::template_empty_line => {
+                    spans.push(item.as_span());
+                    template.lines.push(TemplateLine {
+                        line: item.as_span().start_pos().line_col().0,
+                        ..TemplateLine::default()
+                    });
                 }
-                Rule::template_empty_line => template.lines.push(TemplateLine::default()),
                 Rule::EOI => {}
                 _ => unreachable!(),
             }
         }
-        template
+        Ok(template)
     }
 }
 
 impl Template {
+    /// The `Source` of the line at `index`. The first call builds them for all the
+    /// lines, later ones share them unless the template was changed since.
+    pub(crate) fn source(&self, index: usize) -> Arc<Source> {
+        let line = &self.lines[index];
+        let matches = |source: &Source| {
+            source.template == self.name
+                && source.file.as_deref() == self.file.as_deref()
+                && source.line == line.line
+        };
+        let sources = self.sources.get_or_init(|| {
+            let lines = self.lines.iter();
+            lines.map(|line| Arc::new(self.new_source(line))).collect()
+        });
+        match sources.get(index) {
+            Some(source) if matches(source) => source.clone(),
+            _ => Arc::new(self.new_source(line)),
+        }
+    }
+
+    fn new_source(&self, line: &TemplateLine) -> Source {
+        Source {
+            template: Cow::Owned(self.name.clone()),
+            file: self.file.clone().map(Cow::Owned),
+            line: line.line,
+        }
+    }
+
+    /// The names of all placeholders in the template, including those only used in
+    /// conditions.
     pub fn placeholder_names(&self) -> Vec<String> {
         let mut names = Vec::new();
         for line in &self.lines {
             for segment in &line.segments {
-                match segment {
-                    Segment::Placeholder(ref s) => {
-                        if !names.contains(s) {
-                            names.push(s.clone());
-                        }
-                    }
-                    _ => {}
+                let name = match segment {
+                    Segment::Placeholder(p) => &p.name,
+                    Segment::Condition(name) => name,
+                    _ => continue,
+                };
+                if !names.contains(name) {
+                    names.push(name.clone());
                 }
             }
         }
         names
     }
+
+    /// Replace the lines starting (`${?name}`) and ending (`${/name}`) sections
+    /// with a condition on each of the lines in between. Sections still open at the
+    /// end of the template end with it.
+    ///
+    /// Fails if sections aren't closed in the order they were opened, or an end
+    /// marker isn't on a line of its own. `spans` are the spans of the lines.
+    fn apply_sections(&mut self, spans: &[Span<'_>]) -> Result<(), PestError<Rule>> {
+        let error = |i: usize, message: String| {
+            PestError::new_from_span(ErrorVariant::CustomError { message }, spans[i])
+        };
+        let mut sections: Vec<String> = Vec::new();
+        let mut lines = Vec::with_capacity(self.lines.len());
+        for (i, mut line) in self.lines.drain(..).enumerate() {
+            match line.segments.as_slice() {
+                [Segment::Condition(name)] => {
+                    sections.push(name.clone());
+                    continue;
+                }
+                [Segment::SectionEnd(name)] => match sections.pop() {
+                    Some(ref open) if open == name => continue,
+                    _ => {
+                        let message = format!(
+                            "Unexpected end of section '{}' in template '{}'",
+                            name, self.name
+                        );
+                        return Err(error(i, message));
+                    }
+                },
+                segments => {
+                    if segments.iter().any(|s| matches!(s, Segment::SectionEnd(_))) {
+                        let message = "The end of a section must be on a line of its own";
+                        return Err(error(i, message.into()));
+                    }
+                }
+            }
+            let conditions = sections.iter().cloned().map(Segment::Condition);
+            line.segments.splice(0..0, conditions);
+            lines.push(line);
+        }
+        self.lines = lines;
+        Ok(())
+    }
 }
 
 #[derive(Debug, Default, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct TemplateLine {
     pub indentation: String,
     pub segments: Vec<Segment>,
+
+    /// The line of the file this was parsed from, starting from 1.
+    pub line: usize,
 }
 
 impl From<&str> for TemplateLine {
@@ -59,12 +227,14 @@ impl From<&str> for TemplateLine {
         TemplateLine {
             indentation: "".into(),
             segments: parse_phase2(s).unwrap(),
+            line: 0,
         }
     }
 }
 
 impl<'a> From<Pair<'a, Rule>> for TemplateLine {
     fn from(pair: Pair<'a, Rule>) -> TemplateLine {
+        let line = pair.as_span().start_pos().line_col().0;
         let mut indentation = String::new();
         let mut segments = vec![];
         for item in pair.into_inner() {
@@ -77,13 +247,14 @@ impl<'a> From<Pair<'a, Rule>> for TemplateLine {
         TemplateLine {
             indentation,
             segments,
+            line,
         }
     }
 }
 
 #[cfg(test)]
 mod tests {
-    use crate::parser::parse;
+    use crate::parser::{parse, segment::Segment};
 
     #[test]
     fn empty_template() {
@@ -143,6 +314,17 @@ mod tests {
         assert_debug_snapshot_matches!("template.ignore_inner_template", templates);
     }
 
+    #[test]
+    fn terminator_keeping_newline() {
+        let templates = parse("main =\n    content\n----+\n").unwrap();
+
+        assert_eq!(templates[0].indent_ignored, 4);
+        assert_eq!(
+            templates[0].lines[0].segments,
+            &[Segment::Content("content".into()), Segment::EndOfInput]
+        );
+    }
+
     #[test]
     fn invalid_start() {
         assert!(parse("main\n  content\n--").is_err())
@@ -152,4 +334,97 @@ mod tests {
     fn invalid_end() {
         assert!(parse("main =\n content\n").is_err())
     }
+
+    #[test]
+    fn sections_add_conditions_to_their_lines() {
+        let templates =
+            parse("main =\n    ${?a}\n    x\n    ${?b}\n    y\n    ${/b}\n    ${/a}\n    z\n----")
+                .unwrap();
+        let lines = &templates[0].lines;
+
+        assert_eq!(lines.len(), 3);
+        assert_eq!(
+            lines[0].segments,
+            &[Segment::Condition("a".into()), Segment::Content("x".into())]
+        );
+        assert_eq!(
+            lines[1].segments,
+            &[
+                Segment::Condition("a".into()),
+                Segment::Condition("b".into()),
+                Segment::Content("y".into()),
+            ]
+        );
+        assert_eq!(lines[2].segments, &[Segment::Content("z".into())]);
+        assert_eq!(templates[0].placeholder_names(), vec!["a", "b"]);
+    }
+
+    #[test]
+    fn sections_must_be_closed_in_order() {
+        let error = parse("main =\n    ${?a}\n    ${?b}\n    ${/a}\n    ${/b}\n----").unwrap_err();
+
+        assert_eq!(
+            error.line_col,
+            pest::error::LineColLocation::Span((4, 1), (4, 11))
+        );
+        assert!(error
+            .to_string()
+            .contains("Unexpected end of section 'a' in template 'main'"));
+    }
+
+    #[test]
+    fn section_ends_must_be_on_their_own_line() {
+        let error = parse("main = ${?a}x${/a}").unwrap_err();
+        assert!(error
+            .to_string()
+            .contains("The end of a section must be on a line of its own"));
+
+        let error = parse("main =\n    ${?a}\n    x ${/a}\n----").unwrap_err();
+        assert_eq!(
+            error.line_col,
+            pest::error::LineColLocation::Span((3, 1), (3, 13))
+        );
+    }
+
+    #[test]
+    fn shares_sources_between_blocks() {
+        use crate::block::{Block, LineSegment};
+        use std::sync::Arc;
+
+        let mut templates = parse("main =\n    a\n    b\n----").unwrap();
+        let source = |block: &Block, i: usize| match &block.lines().nth(i).unwrap().0[0] {
+            LineSegment::Source(source) => source.clone(),
+            _ => unreachable!(),
+        };
+        let first = Block::from(&templates[0]);
+        let second = Block::from(&templates[0]);
+        assert!(Arc::ptr_eq(&source(&first, 0), &source(&second, 0)));
+        assert!(Arc::ptr_eq(&source(&first, 1), &source(&second, 1)));
+        assert_eq!(source(&first, 1).line, 3);
+
+        // Changing the template doesn't leave blocks with stale sources
+        templates[0].file = Some("main.jens".into());
+        let third = Block::from(&templates[0]);
+        assert_eq!(source(&third, 0).file.as_deref(), Some("main.jens"));
+    }
+
+    #[test]
+    fn declares_languages() {
+        use crate::escape::Language;
+
+        let templates =
+            parse("a = x\n@language rust\nb = x\nc:html = x\n@language typescript\nd = x").unwrap();
+        let languages: Vec<_> = templates.iter().map(|t| t.language).collect();
+
+        assert_eq!(
+            languages,
+            vec![
+                None,
+                Some(Language::Rust),
+                Some(Language::Html),
+                Some(Language::JavaScript)
+            ]
+        );
+        assert!(parse("@language cobol\na = x").is_err());
+    }
 }
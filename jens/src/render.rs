@@ -0,0 +1,2053 @@
+use crate::{
+    block::{Block, Group, Indent, Line, LineSegment, Placeholder, Slot, Source, Wrapped},
+    coverage::Coverage,
+    escape::{Escaper, Language},
+    ident::Symbol,
+    resolver::Resolver,
+};
+use std::{
+    cell::OnceCell,
+    collections::{HashMap, HashSet},
+    fmt::{self, Write},
+    iter, mem,
+    rc::Rc,
+    sync::Arc,
+};
+use unicode_segmentation::UnicodeSegmentation;
+use unicode_width::UnicodeWidthStr;
+
+/// Replace the text of a line with whitespace of the same display width, so that
+/// text written after it lines up with the end of the line.
+///
+/// Width is measured per grapheme cluster, so wide characters (such as emoji or CJK
+/// text) are replaced by two spaces and combining marks by none. Tabs are preserved,
+/// unless a `tab_width` is given, in which case they are expanded to spaces.
+pub(crate) fn replace_chars_with_whitespace(line: &str, tab_width: Option<usize>) -> String {
+    let mut out = String::with_capacity(line.len());
+    let mut column = 0;
+    for grapheme in line.graphemes(true) {
+        let width = match (grapheme, tab_width) {
+            ("\t", None) => {
+                out.push('\t');
+                continue;
+            }
+            ("\t", Some(tab_width)) => {
+                let tab_width = tab_width.max(1);
+                tab_width - column % tab_width
+            }
+            (grapheme, _) => grapheme.width(),
+        };
+        out.extend(iter::repeat_n(' ', width));
+        column += width;
+    }
+    out
+}
+
+/// The whitespace at the start of a line.
+pub(crate) fn leading_whitespace(line: &str) -> &str {
+    let text = line.trim_start_matches([' ', '\t']);
+    &line[..line.len() - text.len()]
+}
+
+/// Turns a `Block` into text.
+///
+/// Placeholders that are still unset when the block is rendered are looked up in the
+/// renderer's `Resolver`. If the resolver has no value for a placeholder, it is
+/// written out as-is (`${name}`).
+pub struct Renderer<'a> {
+    resolver: &'a dyn Resolver,
+    tab_width: Option<usize>,
+    indent_unit: &'a str,
+    remove_empty_placeholder_lines: bool,
+    max_blank_lines: Option<usize>,
+    width: Option<usize>,
+    source_comments: Option<(&'a str, &'a str)>,
+    coverage: Option<&'a Coverage>,
+}
+
+impl<'a> Default for Renderer<'a> {
+    fn default() -> Self {
+        Renderer {
+            resolver: &(),
+            tab_width: None,
+            indent_unit: "    ",
+            remove_empty_placeholder_lines: false,
+            max_blank_lines: None,
+            width: None,
+            source_comments: None,
+            coverage: None,
+        }
+    }
+}
+
+impl<'a> Renderer<'a> {
+    pub fn new() -> Self {
+        Renderer::default()
+    }
+
+    /// Use `resolver` to fill placeholders encountered while rendering.
+    pub fn resolver(mut self, resolver: &'a dyn Resolver) -> Self {
+        self.resolver = resolver;
+        self
+    }
+
+    /// Expand tabs to spaces, with tab stops every `tab_width` columns, in the
+    /// whitespace used to line up embedded blocks. By default tabs are kept as-is.
+    pub fn tab_width(mut self, tab_width: usize) -> Self {
+        self.tab_width = Some(tab_width);
+        self
+    }
+
+    /// The whitespace added for each level of indentation by `Indent::Hanging`.
+    /// Defaults to four spaces.
+    pub fn indent_unit(mut self, indent_unit: &'a str) -> Self {
+        self.indent_unit = indent_unit;
+        self
+    }
+
+    /// Leave out lines which consist of only whitespace and placeholders, when all
+    /// of those placeholders are filled with blocks that render nothing (such as
+    /// `Block::empty()`), instead of leaving a blank line in their place.
+    pub fn remove_empty_placeholder_lines(mut self, remove: bool) -> Self {
+        self.remove_empty_placeholder_lines = remove;
+        self
+    }
+
+    /// Collapse runs of more than `max` consecutive blank lines down to `max`. Lines
+    /// with nothing but indentation on them are blank, since indentation is only
+    /// written ahead of text.
+    pub fn max_blank_lines(mut self, max: usize) -> Self {
+        self.max_blank_lines = Some(max);
+        self
+    }
+
+    /// The width that lines should fit within, which decides whether each `Group` is
+    /// written on one line or broken across several. Without a width, groups are
+    /// always written on one line.
+    pub fn width(mut self, width: usize) -> Self {
+        self.width = Some(width);
+        self
+    }
+
+    /// End each line that came from a template with a comment saying where, between
+    /// `open` and `close` (such as `("// ", "")` or `("<!-- ", " -->")`). See `Source`.
+    pub fn source_comments(mut self, open: &'a str, close: &'a str) -> Self {
+        self.source_comments = Some((open, close));
+        self
+    }
+
+    /// Record the template lines written, and the placeholders filled by the
+    /// resolver, to `coverage`. See `File::with_coverage`.
+    pub fn coverage(mut self, coverage: &'a Coverage) -> Self {
+        self.coverage = Some(coverage);
+        self
+    }
+
+    /// Render a block to a new string.
+    ///
+    /// # Panics
+    ///
+    /// If the block can't be rendered, because it contains a `Slot` that hasn't
+    /// been filled or contains itself. See `try_render`.
+    pub fn render(&self, block: &Block) -> String {
+        self.try_render(block)
+            .unwrap_or_else(|error| panic!("{}", error))
+    }
+
+    /// Render a block to a new string, or return an error if it contains a `Slot`
+    /// that hasn't been filled or contains itself.
+    pub fn try_render(&self, block: &Block) -> Result<String, RenderError> {
+        let mut out = String::new();
+        let mut writer = self.writer(&mut out, block, "");
+        if writer.write_block(block, "").is_err() {
+            return Err(writer.error.take().unwrap());
+        }
+        Ok(out)
+    }
+
+    /// Render a block to a new string, along with the template lines that each line
+    /// of the output came from. Fails like `try_render`.
+    pub fn render_with_source_map(
+        &self,
+        block: &Block,
+    ) -> Result<(String, SourceMap), RenderError> {
+        let mut out = String::new();
+        let mut writer = self.writer(&mut out, block, "");
+        writer.source_map = Some(Vec::new());
+        if writer.write_block(block, "").is_err() {
+            return Err(writer.error.take().unwrap());
+        }
+        let lines = writer.source_map.take().unwrap();
+        Ok((out, SourceMap { lines }))
+    }
+
+    /// Write a block to `out`. Every line after the first is preceded by `prefix`.
+    ///
+    /// Fails if `out` does, or if the block contains a `Slot` that hasn't been filled.
+    pub fn write_to<W: Write>(&self, out: &mut W, block: &Block, prefix: &str) -> fmt::Result {
+        self.writer(out, block, prefix).write_block(block, prefix)
+    }
+
+    /// Like `write_to`, but panics if the block contains a `Slot` that hasn't been
+    /// filled, so that only `out` failing is reported as a `fmt::Error`.
+    pub(crate) fn write_or_panic<W: Write>(
+        &self,
+        out: &mut W,
+        block: &Block,
+        prefix: &str,
+    ) -> fmt::Result {
+        let mut writer = self.writer(out, block, prefix);
+        let result = writer.write_block(block, prefix);
+        if let Some(error) = writer.error.take() {
+            panic!("{}", error);
+        }
+        result
+    }
+
+    fn writer<'w, W: Write>(
+        &'w self,
+        out: &'w mut W,
+        block: &Block,
+        prefix: &str,
+    ) -> Writer<'w, W> {
+        Writer {
+            out,
+            options: self,
+            line: String::from(prefix),
+            written: prefix.len(),
+            literal: 0,
+            literal_indent: String::new(),
+            blank: true,
+            blank_lines: 0,
+            line_ended: false,
+            resolving: Vec::new(),
+            expanding: Vec::new(),
+            output_line: 0,
+            started_lines: 0,
+            measuring: None,
+            root: block.clone(),
+            hoisted: Rc::default(),
+            symbols: vec![SymbolScope::default()],
+            error: None,
+            source: None,
+            source_map: None,
+        }
+    }
+}
+
+/// The template line that each line of rendered output came from, from
+/// `Renderer::render_with_source_map`.
+#[derive(Clone, PartialEq, Eq, Debug, Default)]
+pub struct SourceMap {
+    /// The source of each line of output, starting from the first. Lines that
+    /// didn't come from a template (such as text values) have the source of the
+    /// line they were inserted into, if any.
+    pub lines: Vec<Option<Arc<Source>>>,
+}
+
+impl SourceMap {
+    /// The map as JSON, with an entry for each line of output that has a source.
+    /// Line numbers start from 1, and `file` is `null` if it isn't known:
+    ///
+    /// ```json
+    /// {"lines": [
+    ///   {"line": 1, "template": "struct_def", "file": "types.jens", "source_line": 4}
+    /// ]}
+    /// ```
+    pub fn to_json(&self) -> String {
+        let string = |s: &str| format!("\"{}\"", Language::Json.escape(s));
+        let entries: Vec<_> = self
+            .lines
+            .iter()
+            .enumerate()
+            .filter_map(|(i, source)| {
+                let source = source.as_ref()?;
+                let file = source.file.as_deref().map_or("null".into(), string);
+                Some(format!(
+                    "  {{\"line\": {}, \"template\": {}, \"file\": {}, \"source_line\": {}}}",
+                    i + 1,
+                    string(&source.template),
+                    file,
+                    source.line
+                ))
+            })
+            .collect();
+        if entries.is_empty() {
+            return "{\"lines\": []}".into();
+        }
+        format!("{{\"lines\": [\n{}\n]}}", entries.join(",\n"))
+    }
+}
+
+/// Why a block couldn't be rendered.
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub enum RenderError {
+    /// The block contains a `Slot` (with this name) that was never filled.
+    UnfilledSlot(String),
+
+    /// The block contains a `Slot` (with this name) that was filled with a block
+    /// containing the same slot, so it would never finish rendering.
+    RecursiveSlot(String),
+}
+
+impl fmt::Display for RenderError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            RenderError::UnfilledSlot(name) => write!(f, "Slot '{}' was never filled", name),
+            RenderError::RecursiveSlot(name) => write!(f, "Slot '{}' contains itself", name),
+        }
+    }
+}
+
+impl std::error::Error for RenderError {}
+
+/// A `fmt::Write` that only counts the bytes written to it.
+struct Counter(usize);
+
+impl Write for Counter {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        self.0 += s.len();
+        Ok(())
+    }
+}
+
+/// The names given to symbols in a scope, and how many symbols have been named from
+/// each base name (including in the scopes around it).
+#[derive(Clone, Default)]
+struct SymbolScope {
+    names: HashMap<usize, String>,
+    counts: HashMap<String, usize>,
+}
+
+impl SymbolScope {
+    fn name(&mut self, symbol: &Symbol) -> &str {
+        let counts = &mut self.counts;
+        self.names.entry(symbol.id()).or_insert_with(|| {
+            let count = counts.entry(symbol.base().to_owned()).or_insert(0);
+            *count += 1;
+            format!("{}_{}", symbol.base(), count)
+        })
+    }
+}
+
+/// The text at the start of a line of output.
+struct LinePrefix {
+    text: String,
+
+    /// How many bytes at the start of `text` come from the line prefixes of blocks
+    /// (see `Block::prefix_lines`), rather than being whitespace.
+    literal: usize,
+}
+
+impl LinePrefix {
+    fn new(text: String) -> Self {
+        LinePrefix { text, literal: 0 }
+    }
+}
+
+/// Progress through one block that is being written.
+struct Frame {
+    block: Block,
+    line: usize,
+    segment: usize,
+
+    /// Whether the separator before the current line has been dealt with.
+    line_started: bool,
+
+    /// Whether any lines of this block have been written (rather than removed).
+    wrote_line: bool,
+
+    /// Written at the start of every line after the first.
+    prefix: LinePrefix,
+
+    /// The leading indentation of the line this block was embedded in. If the
+    /// block ends with a newline, anything following it is indented by this.
+    resume: LinePrefix,
+
+    /// The writer's line prefix from before this block added its own, restored
+    /// when the block ends part-way through a line, along with the number of lines
+    /// started before it.
+    outer_literal: Option<(usize, usize, String)>,
+
+    /// What this block is the value of, if anything.
+    expanded: Expanded,
+
+    /// The source of the line this block was embedded in, which is the source of
+    /// any of its lines that don't have their own.
+    outer_source: Option<Arc<Source>>,
+
+    /// The escaper of the innermost block around this point that has one, which
+    /// text values filling placeholders in this block are escaped with.
+    escaper: Option<Arc<dyn Escaper>>,
+
+    /// The columns that the alignment markers on each line of this block are padded
+    /// to, measured when the first marker is written inside this block. Lines that
+    /// aren't part of a run of consecutive lines with markers have none.
+    align: Option<Vec<Vec<usize>>>,
+
+    /// The line of output and the column that the current line started at.
+    line_start: (usize, usize),
+
+    /// The number of alignment markers in the current line that have been written.
+    markers: usize,
+}
+
+/// Where the block of a frame came from, if it's undone when the block ends.
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Expanded {
+    Nothing,
+    Placeholder,
+    Slot,
+}
+
+impl Frame {
+    fn new(block: Block, prefix: LinePrefix, resume: LinePrefix, expanded: Expanded) -> Self {
+        Frame {
+            block,
+            line: 0,
+            segment: 0,
+            line_started: false,
+            wrote_line: false,
+            prefix,
+            resume,
+            outer_literal: None,
+            expanded,
+            outer_source: None,
+            escaper: None,
+            align: None,
+            line_start: (0, 0),
+            markers: 0,
+        }
+    }
+}
+
+struct Writer<'a, W> {
+    out: &'a mut W,
+    options: &'a Renderer<'a>,
+
+    /// Everything written so far on the current line of output, which determines
+    /// the indentation of a block embedded at this point.
+    line: String,
+
+    /// How much of `line` has actually been written to `out`. Indentation at the
+    /// start of a line is only written once something follows it, so that blank
+    /// lines never end in whitespace.
+    written: usize,
+
+    /// How many bytes at the start of `line` come from the line prefixes of blocks.
+    /// Unlike the rest of the line, these are kept in the indentation of blocks
+    /// embedded in it.
+    literal: usize,
+
+    /// What the first `literal` bytes of `line` are replaced with in the indentation
+    /// of embedded blocks. This is the same text, unless a block with a line prefix
+    /// started part-way through the line.
+    literal_indent: String,
+
+    /// Whether nothing has been written on the current line yet.
+    blank: bool,
+
+    /// The number of blank lines immediately before the current line.
+    blank_lines: usize,
+
+    /// Whether the current line was started by an `EndOfInput`, and nothing has been
+    /// written on it yet.
+    line_ended: bool,
+
+    /// Names of the placeholders currently being resolved, used to avoid rendering a
+    /// value that (directly or indirectly) refers back to itself forever.
+    resolving: Vec<String>,
+
+    /// The slots whose values are currently being written, used to fail rather than
+    /// write a slot that (directly or indirectly) contains itself forever.
+    expanding: Vec<Slot>,
+
+    /// The number of lines of output that have been ended.
+    output_line: usize,
+
+    /// The number of lines started, including those which follow a line ended by an
+    /// `EndOfInput` without another newline.
+    started_lines: usize,
+
+    /// Set when only measuring the columns of the alignment markers in a line, to
+    /// collect them. Writing stops at the end of the line.
+    measuring: Option<Vec<usize>>,
+
+    /// The block being rendered.
+    root: Block,
+
+    /// The items hoisted by the blocks in `root`, collected the first time they
+    /// are needed.
+    hoisted: Rc<OnceCell<HashMap<String, Block>>>,
+
+    /// The scopes that symbols are named in, innermost last.
+    symbols: Vec<SymbolScope>,
+
+    /// The reason writing failed, if it was the block rather than `out` at fault.
+    error: Option<RenderError>,
+
+    /// Where the current line of output came from.
+    source: Option<Arc<Source>>,
+
+    /// The sources of the lines of output so far, if they are being recorded.
+    source_map: Option<Vec<Option<Arc<Source>>>>,
+}
+
+/// The placeholders and slots whose values have already been checked for whether
+/// they render anything.
+#[derive(Default)]
+struct Checked {
+    placeholders: Vec<String>,
+    slots: Vec<Slot>,
+}
+
+impl<'a, W: Write> Writer<'a, W> {
+    /// Write a block and everything nested inside it.
+    ///
+    /// Nested blocks are tracked on an explicit stack rather than by recursion, so
+    /// arbitrarily deep trees can be written without overflowing the call stack.
+    fn write_block(&mut self, block: &Block, prefix: &str) -> fmt::Result {
+        let mut stack = Vec::new();
+        self.push_frame(
+            &mut stack,
+            Frame::new(
+                block.clone(),
+                LinePrefix::new(prefix.into()),
+                LinePrefix::new(prefix.into()),
+                Expanded::Nothing,
+            ),
+        );
+
+        while let Some(frame) = stack.last_mut() {
+            let lines = frame.block.lines().as_slice();
+            if frame.line == lines.len() {
+                match frame.expanded {
+                    Expanded::Placeholder => {
+                        self.resolving.pop();
+                    }
+                    Expanded::Slot => {
+                        self.expanding.pop();
+                    }
+                    Expanded::Nothing => {}
+                }
+                if frame.block.is_symbol_scope() {
+                    self.symbols.pop();
+                }
+                // Anything following a block that ended its last line is indented
+                // like the line the block was embedded in.
+                if self.line_ended {
+                    let resume = mem::replace(&mut frame.resume, LinePrefix::new(String::new()));
+                    self.start_line(&resume);
+                    self.source = frame.outer_source.take();
+                } else if let Some((started, literal, literal_indent)) = frame.outer_literal.take()
+                {
+                    // A line started since then begins with the outer prefix instead
+                    self.literal = if started == self.started_lines {
+                        literal
+                    } else {
+                        literal_indent.len()
+                    };
+                    self.literal_indent = literal_indent;
+                }
+                stack.pop();
+                continue;
+            }
+
+            let segments = &lines[frame.line].0;
+            if !frame.line_started {
+                if !self.conditions_hold(segments, &frame.escaper) {
+                    frame.line += 1;
+                    continue;
+                }
+                if self.options.remove_empty_placeholder_lines && self.is_removable(segments) {
+                    frame.line += 1;
+                    continue;
+                }
+                if frame.wrote_line {
+                    // A line which has already been ended doesn't need another newline
+                    if !self.line_ended {
+                        self.newline()?;
+                    }
+                    self.start_line(&frame.prefix);
+                    self.line_ended = false;
+                    self.source = frame.outer_source.clone();
+                }
+                frame.line_started = true;
+                frame.wrote_line = true;
+                frame.line_start = (self.output_line, self.column());
+                frame.markers = 0;
+            }
+
+            if frame.segment == segments.len() {
+                frame.line += 1;
+                frame.segment = 0;
+                frame.line_started = false;
+                continue;
+            }
+
+            let segment = &segments[frame.segment];
+            frame.segment += 1;
+            if let LineSegment::Group(g) = segment {
+                let group = g.clone();
+                let block = self.layout(&group, &Self::following_text(&stack));
+                let prefix = self.indentation(&block);
+                let resume = self.resume_indentation();
+                self.push_frame(
+                    &mut stack,
+                    Frame::new(block, prefix, resume, Expanded::Nothing),
+                );
+                continue;
+            }
+            if let LineSegment::Align = segment {
+                self.align(&mut stack)?;
+                continue;
+            }
+            if let LineSegment::Source(source) = segment {
+                if let (Some(coverage), None) = (self.options.coverage, &self.measuring) {
+                    coverage.record_line(source);
+                }
+                self.source = Some(source.clone());
+                continue;
+            }
+            let nested = match segment {
+                LineSegment::Content(s) => {
+                    self.write_str(s)?;
+                    None
+                }
+                LineSegment::Symbol(symbol) => {
+                    let scope = self.symbols.last_mut().unwrap();
+                    let name = scope.name(symbol).to_owned();
+                    self.write_str(&name)?;
+                    None
+                }
+                LineSegment::Placeholder(p) => match self.resolve(&p.name) {
+                    Some(b) => {
+                        if let (Some(coverage), Some(source), None) =
+                            (self.options.coverage, &self.source, &self.measuring)
+                        {
+                            coverage.record_fill(source, &p.name);
+                        }
+                        self.resolving.push(p.name.clone());
+                        let block = p.fill_escaped(&b, frame.escaper.as_deref());
+                        Some((block, Expanded::Placeholder))
+                    }
+                    None => {
+                        self.write_str(&format!("${{{}}}", p.name))?;
+                        None
+                    }
+                },
+                LineSegment::Block(b) => Some((b.clone(), Expanded::Nothing)),
+                LineSegment::Wrapped(w) => Some((self.wrap(w), Expanded::Nothing)),
+                LineSegment::Hoisted(key) => Some((self.hoisted(key), Expanded::Nothing)),
+                LineSegment::Slot(slot) => match slot.value() {
+                    Some(_) if self.expanding.contains(slot) => {
+                        self.error = Some(RenderError::RecursiveSlot(slot.name().into()));
+                        return Err(fmt::Error);
+                    }
+                    Some(b) => {
+                        self.expanding.push(slot.clone());
+                        // Escaped like the value of a placeholder would be
+                        let placeholder = Placeholder::new(slot.name());
+                        let block = placeholder.fill_escaped(&b, frame.escaper.as_deref());
+                        Some((block, Expanded::Slot))
+                    }
+                    None => {
+                        self.error = Some(RenderError::UnfilledSlot(slot.name().into()));
+                        return Err(fmt::Error);
+                    }
+                },
+                LineSegment::Group(_) | LineSegment::Align | LineSegment::Source(_) => {
+                    unreachable!()
+                }
+                LineSegment::Condition(_) => None,
+                LineSegment::EndOfInput => {
+                    self.newline()?;
+                    self.start_line(&frame.prefix);
+                    self.line_ended = true;
+                    None
+                }
+            };
+
+            if let Some((block, expanded)) = nested {
+                let prefix = self.indentation(&block);
+                let resume = self.resume_indentation();
+                self.push_frame(&mut stack, Frame::new(block, prefix, resume, expanded));
+            }
+        }
+        self.write_literal()?;
+        if !self.blank || self.output_line > 0 {
+            self.end_line_source()?;
+        }
+        Ok(())
+    }
+
+    /// Start writing a block at the current position, adding its line prefix (if
+    /// it has one) to the current line and to the start of each of its lines.
+    fn push_frame(&mut self, stack: &mut Vec<Frame>, mut frame: Frame) {
+        frame.outer_source = self.source.clone();
+        frame.escaper = match frame.block.escaper() {
+            Some(escaper) => Some(escaper.clone()),
+            None => stack.last().and_then(|outer| outer.escaper.clone()),
+        };
+        if frame.block.is_symbol_scope() {
+            let scope = self.symbols.last().cloned().unwrap_or_default();
+            self.symbols.push(scope);
+        }
+        let block_prefix = frame.block.line_prefix();
+        if !block_prefix.is_empty() && !frame.block.is_empty() {
+            frame.prefix.text.push_str(block_prefix);
+            frame.prefix.literal = frame.prefix.text.len();
+
+            self.line.push_str(block_prefix);
+            let literal_indent = mem::replace(&mut self.literal_indent, frame.prefix.text.clone());
+            frame.outer_literal = Some((self.started_lines, self.literal, literal_indent));
+            self.literal = self.line.len();
+        }
+        stack.push(frame);
+    }
+
+    /// Write any of the line prefix of the current line that hasn't been written
+    /// yet, without trailing whitespace, so that blank lines still get their prefix.
+    fn write_literal(&mut self) -> fmt::Result {
+        if self.written >= self.literal {
+            return Ok(());
+        }
+        let prefix = self.line[self.written..self.literal].trim_end();
+        if !prefix.is_empty() {
+            self.out.write_str(prefix)?;
+            self.blank = false;
+        }
+        self.written = self.literal;
+        Ok(())
+    }
+
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        if s.is_empty() {
+            return Ok(());
+        }
+        self.line_ended = false;
+        // Whitespace at the start of a line is held back like indentation, so lines
+        // with nothing else on them are blank
+        if self.blank && s.chars().all(|c| c == ' ' || c == '\t') {
+            self.line.push_str(s);
+            return Ok(());
+        }
+        self.out.write_str(&self.line[self.written..])?;
+        self.out.write_str(s)?;
+        self.line.push_str(s);
+        self.written = self.line.len();
+        self.blank = false;
+        Ok(())
+    }
+
+    /// End the current line, unless it is blank and there have already been as
+    /// many blank lines in a row as allowed.
+    fn newline(&mut self) -> fmt::Result {
+        if self.measuring.is_some() {
+            return Err(fmt::Error);
+        }
+        self.output_line += 1;
+        self.write_literal()?;
+        if !self.blank {
+            self.blank_lines = 0;
+        } else {
+            self.blank_lines += 1;
+            if let Some(max) = self.options.max_blank_lines {
+                if self.blank_lines > max {
+                    return Ok(());
+                }
+            }
+        }
+        self.end_line_source()?;
+        self.out.write_str("\n")
+    }
+
+    /// Record where the line of output being ended came from, and write it in a
+    /// comment at the end of the line if asked to.
+    fn end_line_source(&mut self) -> fmt::Result {
+        if let Some(source_map) = &mut self.source_map {
+            source_map.push(self.source.clone());
+        }
+        if let (Some((open, close)), Some(source)) = (self.options.source_comments, &self.source) {
+            if !self.blank && self.measuring.is_none() {
+                write!(self.out, " {}{}{}", open, source, close)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// The display width of the current line so far.
+    fn column(&self) -> usize {
+        let tab_width = Some(self.options.tab_width.unwrap_or(4));
+        replace_chars_with_whitespace(&self.line, tab_width).len()
+    }
+
+    /// Pad the current line to the column of the alignment marker being written.
+    ///
+    /// The marker belongs to the innermost block whose current line started on this
+    /// line of output, and is part of a run of lines with markers.
+    fn align(&mut self, stack: &mut [Frame]) -> fmt::Result {
+        let column = self.column();
+        if let Some(columns) = &mut self.measuring {
+            columns.push(column);
+            return Ok(());
+        }
+        for frame in stack.iter_mut().rev() {
+            if frame.line_start.0 != self.output_line {
+                continue;
+            }
+            if frame.align.is_none() {
+                frame.align = Some(self.measure_columns(&frame.block, &frame.escaper));
+            }
+            let targets = &frame.align.as_ref().unwrap()[frame.line];
+            if targets.is_empty() {
+                continue;
+            }
+            let target = targets.get(frame.markers).map(|t| frame.line_start.1 + t);
+            frame.markers += 1;
+            if let Some(target) = target {
+                let padding = target.saturating_sub(column);
+                self.write_str(&" ".repeat(padding))?;
+            }
+            return Ok(());
+        }
+        Ok(())
+    }
+
+    /// The columns, relative to the start of each line, that the alignment markers
+    /// on the lines of a block are padded to.
+    ///
+    /// Each column starts after the widest cell before it in the run of consecutive
+    /// lines with markers. Lines that won't be written are skipped over.
+    fn measure_columns(
+        &self,
+        block: &Block,
+        escaper: &Option<Arc<dyn Escaper>>,
+    ) -> Vec<Vec<usize>> {
+        let mut columns = Vec::with_capacity(block.lines().len());
+        let mut run: Vec<(usize, Vec<usize>)> = Vec::new();
+        for (i, line) in block.lines().enumerate() {
+            columns.push(Vec::new());
+            let segments = &line.0;
+            let removed = !self.conditions_hold(segments, escaper)
+                || (self.options.remove_empty_placeholder_lines && self.is_removable(segments));
+            if removed {
+                continue;
+            }
+            let markers = self.measure_line(line);
+            if markers.is_empty() {
+                Self::align_run(&mut run, &mut columns);
+            } else {
+                run.push((i, markers));
+            }
+        }
+        Self::align_run(&mut run, &mut columns);
+        columns
+    }
+
+    /// Work out the columns for a run of lines, given the unpadded position of the
+    /// markers on each of them.
+    fn align_run(run: &mut Vec<(usize, Vec<usize>)>, columns: &mut [Vec<usize>]) {
+        if run.len() > 1 {
+            let mut column = 0;
+            let mut start = vec![0; run.len()];
+            let count = run
+                .iter()
+                .map(|(_, markers)| markers.len())
+                .max()
+                .unwrap_or(0);
+            for marker in 0..count {
+                let widest = run
+                    .iter()
+                    .zip(&start)
+                    .filter_map(|((_, markers), start)| Some(markers.get(marker)? - start))
+                    .max()
+                    .unwrap();
+                column += widest;
+                for ((line, markers), start) in run.iter().zip(&mut start) {
+                    if let Some(&position) = markers.get(marker) {
+                        columns[*line].push(column);
+                        *start = position;
+                    }
+                }
+            }
+        }
+        run.clear();
+    }
+
+    /// The positions of the alignment markers in a line, before any padding, up to
+    /// the end of the first line of output it writes.
+    fn measure_line(&self, line: &Line) -> Vec<usize> {
+        let mut writer = Writer {
+            out: &mut Counter(0),
+            options: self.options,
+            line: String::new(),
+            written: 0,
+            literal: 0,
+            literal_indent: String::new(),
+            blank: true,
+            blank_lines: 0,
+            line_ended: false,
+            resolving: self.resolving.clone(),
+            expanding: self.expanding.clone(),
+            output_line: 0,
+            started_lines: 0,
+            measuring: Some(Vec::new()),
+            root: self.root.clone(),
+            hoisted: self.hoisted.clone(),
+            symbols: self.symbols.clone(),
+            error: None,
+            source: None,
+            source_map: None,
+        };
+        // Writing fails at the end of the first line of output
+        let _ = writer.write_block(&Block::new(vec![line.clone()]), "");
+        writer.measuring.unwrap_or_default()
+    }
+
+    /// Start a new line of output (after the newline has been written), indented by
+    /// `prefix`.
+    fn start_line(&mut self, prefix: &LinePrefix) {
+        self.started_lines += 1;
+        self.line.clear();
+        self.line.push_str(&prefix.text);
+        self.literal = prefix.literal;
+        self.literal_indent.clear();
+        self.literal_indent.push_str(&prefix.text[..prefix.literal]);
+        self.written = 0;
+        self.blank = true;
+    }
+
+    /// The prefix for the lines after the first of a block embedded at the current
+    /// position.
+    fn indentation(&self, block: &Block) -> LinePrefix {
+        let tab_width = self.options.tab_width;
+        let line = &self.line[self.literal..];
+        let whitespace = match block.indent_mode() {
+            Indent::Column => replace_chars_with_whitespace(line, tab_width),
+            Indent::Leading => replace_chars_with_whitespace(leading_whitespace(line), tab_width),
+            Indent::Hanging => {
+                let leading = leading_whitespace(line);
+                replace_chars_with_whitespace(leading, tab_width) + self.options.indent_unit
+            }
+            Indent::None => String::new(),
+        };
+        self.after_literal(whitespace)
+    }
+
+    /// The leading indentation of the current line, which anything following a
+    /// block that ends with a newline is indented by.
+    fn resume_indentation(&self) -> LinePrefix {
+        let leading = leading_whitespace(&self.line[self.literal..]);
+        self.after_literal(replace_chars_with_whitespace(
+            leading,
+            self.options.tab_width,
+        ))
+    }
+
+    /// Indentation consisting of the current line prefix followed by `whitespace`.
+    fn after_literal(&self, whitespace: String) -> LinePrefix {
+        if self.literal_indent.is_empty() {
+            return LinePrefix::new(whitespace);
+        }
+        LinePrefix {
+            text: self.literal_indent.clone() + &whitespace,
+            literal: self.literal_indent.len(),
+        }
+    }
+
+    /// Lay out a group at the current position, on one line if it fits (along with
+    /// the text following it on the line, up to the next block) or otherwise with an
+    /// item on each line.
+    fn layout(&self, group: &Group, following: &str) -> Block {
+        let mut segments = vec![LineSegment::Content(group.open.clone())];
+        for (i, item) in group.items.iter().enumerate() {
+            if i > 0 {
+                segments.push(LineSegment::from(format!("{} ", group.separator)));
+            }
+            segments.push(LineSegment::Block(item.clone()));
+        }
+        segments.push(LineSegment::Content(group.close.clone()));
+        let flat = Block::new(vec![Line(segments)]);
+        if group.items.is_empty() || self.fits(&flat, following) {
+            return flat;
+        }
+
+        let indent_unit = self.options.indent_unit.to_owned();
+        let mut lines = vec![Line::from(group.open.clone())];
+        for (i, item) in group.items.iter().enumerate() {
+            let mut segments = vec![
+                LineSegment::from(indent_unit.clone()),
+                LineSegment::Block(item.clone()),
+            ];
+            if i + 1 < group.items.len() || group.trailing_separator {
+                segments.push(LineSegment::Content(group.separator.clone()));
+            }
+            lines.push(Line(segments));
+        }
+        lines.push(Line::from(group.close.clone()));
+        Block::new(lines).with_indent(Indent::Leading)
+    }
+
+    /// Reflow text into lines that fit within its width, starting at the current
+    /// column.
+    fn wrap(&self, wrapped: &Wrapped) -> Block {
+        let available = wrapped
+            .width
+            .saturating_sub(self.column() + wrapped.prefix.width());
+        let mut lines = Vec::new();
+        for (i, words) in wrapped.paragraphs().into_iter().enumerate() {
+            if i > 0 {
+                lines.push(Line::from(wrapped.prefix.trim_end()));
+            }
+            let mut text = String::new();
+            let mut width = 0;
+            for word in words {
+                if !text.is_empty() && width + 1 + word.width() > available {
+                    lines.push(Line::from(format!("{}{}", wrapped.prefix, text)));
+                    text.clear();
+                    width = 0;
+                }
+                if !text.is_empty() {
+                    text.push(' ');
+                    width += 1;
+                }
+                text.push_str(word);
+                width += word.width();
+            }
+            lines.push(Line::from(format!("{}{}", wrapped.prefix, text)));
+        }
+        Block::new(lines)
+    }
+
+    /// The text following the current position on the same line, up to the next
+    /// block or placeholder, including text after the end of the blocks it is in.
+    fn following_text(stack: &[Frame]) -> String {
+        let mut text = String::new();
+        for frame in stack.iter().rev() {
+            let lines = frame.block.lines().as_slice();
+            for segment in &lines[frame.line].0[frame.segment..] {
+                match segment {
+                    LineSegment::Content(s) => text.push_str(s),
+                    LineSegment::Condition(_) | LineSegment::Source(_) => {}
+                    _ => return text,
+                }
+            }
+            if frame.line + 1 < lines.len() {
+                break;
+            }
+        }
+        text
+    }
+
+    /// Whether `flat` fits on the current line without going past the renderer's
+    /// width, followed by `following`.
+    fn fits(&self, flat: &Block, following: &str) -> bool {
+        let width = match self.options.width {
+            Some(width) => width,
+            None => return true,
+        };
+        // Nested groups are measured flat too, since they can only fit if this does
+        let renderer = Renderer {
+            width: None,
+            source_comments: None,
+            coverage: None,
+            ..*self.options
+        };
+        let mut text = match renderer.try_render(flat) {
+            Ok(text) => text,
+            Err(_) => return false,
+        };
+        if text.contains('\n') {
+            return false;
+        }
+        text.push_str(following);
+        let tab_width = Some(self.options.tab_width.unwrap_or(4));
+        self.column() + replace_chars_with_whitespace(&text, tab_width).len() <= width
+    }
+
+    /// Whether every condition on a line holds, meaning its placeholder is set (or
+    /// resolves) to a block that renders something once escaped by `escaper`.
+    fn conditions_hold(
+        &self,
+        segments: &[LineSegment],
+        escaper: &Option<Arc<dyn Escaper>>,
+    ) -> bool {
+        segments.iter().all(|segment| match segment {
+            LineSegment::Condition(c) => match c
+                .value
+                .clone()
+                .or_else(|| self.resolve_condition(&c.name, escaper))
+            {
+                Some(value) => !self.renders_nothing(vec![value], Checked::default()),
+                None => false,
+            },
+            _ => true,
+        })
+    }
+
+    /// Whether a line consists of only whitespace and at least one placeholder, with
+    /// every placeholder filled by a block that renders nothing.
+    fn is_removable(&self, segments: &[LineSegment]) -> bool {
+        let mut nested = Vec::new();
+        let mut expanded = Checked::default();
+        if !self.collect_nested(segments, &mut nested, &mut expanded) || nested.is_empty() {
+            return false;
+        }
+        self.renders_nothing(nested, expanded)
+    }
+
+    /// Whether the given blocks, and everything nested inside them, write nothing
+    /// when rendered with lines of empty placeholders removed.
+    fn renders_nothing(&self, mut pending: Vec<Block>, mut expanded: Checked) -> bool {
+        while let Some(block) = pending.pop() {
+            // Blank lines are still written with a line prefix, unless it's whitespace
+            if !block.is_empty() && !block.line_prefix().trim().is_empty() {
+                return false;
+            }
+            let mut blank_lines = 0;
+            for line in block.lines() {
+                let before = pending.len();
+                if !self.collect_nested(&line.0, &mut pending, &mut expanded) {
+                    return false;
+                }
+                if pending.len() == before {
+                    // Lines without any blocks in them are blank lines, and can't be
+                    // removed. A single blank line renders as nothing, but any more
+                    // and there will be a newline between them.
+                    let empty = line.0.iter().all(|segment| match segment {
+                        LineSegment::Content(s) => s.is_empty(),
+                        LineSegment::Source(_) => true,
+                        _ => false,
+                    });
+                    blank_lines += 1;
+                    if !empty || blank_lines > 1 {
+                        return false;
+                    }
+                }
+            }
+        }
+        true
+    }
+
+    /// Add the blocks embedded in or filling placeholders in a line to `nested`.
+    /// Returns false if the line contains anything other than whitespace and blocks.
+    ///
+    /// Resolved placeholders and filled slots are recorded in `expanded`, so that
+    /// each is only checked once, even if its value contains it again.
+    fn collect_nested(
+        &self,
+        segments: &[LineSegment],
+        nested: &mut Vec<Block>,
+        expanded: &mut Checked,
+    ) -> bool {
+        for segment in segments {
+            match segment {
+                LineSegment::Content(s) => {
+                    if !s.chars().all(|c| c == ' ' || c == '\t') {
+                        return false;
+                    }
+                }
+                LineSegment::Placeholder(p) if expanded.placeholders.contains(&p.name) => {}
+                LineSegment::Placeholder(p) => match self.resolve(&p.name) {
+                    Some(b) => {
+                        expanded.placeholders.push(p.name.clone());
+                        nested.push(b);
+                    }
+                    None => return false,
+                },
+                LineSegment::Block(b) => nested.push(b.clone()),
+                LineSegment::Hoisted(key) => nested.push(self.hoisted(key)),
+                LineSegment::Source(_) => {}
+                LineSegment::Slot(slot) if expanded.slots.contains(slot) => {}
+                LineSegment::Slot(slot) => match slot.value() {
+                    Some(b) => {
+                        expanded.slots.push(slot.clone());
+                        nested.push(b);
+                    }
+                    None => return false,
+                },
+                LineSegment::Condition(_) | LineSegment::Align => {}
+                LineSegment::Group(_)
+                | LineSegment::Wrapped(_)
+                | LineSegment::Symbol(_)
+                | LineSegment::EndOfInput => return false,
+            }
+        }
+        true
+    }
+
+    /// The items hoisted under `key` by the blocks being rendered.
+    fn hoisted(&self, key: &str) -> Block {
+        let hoisted = self.hoisted.get_or_init(|| self.collect_hoisted());
+        hoisted.get(key).cloned().unwrap_or_default()
+    }
+
+    /// Walk the whole tree being rendered to collect the items hoisted out of it,
+    /// each joined into a block per key, sorted and without duplicates.
+    fn collect_hoisted(&self) -> HashMap<String, Block> {
+        let mut items: HashMap<String, Vec<Block>> = HashMap::new();
+        let mut visited = HashSet::new();
+        // Keeps the blocks that have been walked alive, so their lines aren't freed
+        // and their addresses reused by blocks which haven't
+        let mut walked = Vec::new();
+        let mut expanded = Vec::new();
+        let mut pending = vec![self.root.clone()];
+        while let Some(block) = pending.pop() {
+            for (key, item) in block.hoisted_items() {
+                items.entry(key.clone()).or_default().push(item.clone());
+            }
+            // Blocks that share their lines only need to be walked once
+            let lines = block.lines().as_slice();
+            if !visited.insert((lines.as_ptr(), lines.len())) {
+                continue;
+            }
+            for line in lines {
+                for segment in &line.0 {
+                    match segment {
+                        LineSegment::Block(b) => pending.push(b.clone()),
+                        LineSegment::Group(g) => pending.extend(g.items.iter().cloned()),
+                        LineSegment::Slot(slot) => pending.extend(slot.value()),
+                        LineSegment::Placeholder(p) if !expanded.contains(&p.name) => {
+                            expanded.push(p.name.clone());
+                            pending.extend(self.options.resolver.resolve(&p.name));
+                        }
+                        _ => {}
+                    }
+                }
+            }
+            walked.push(block);
+        }
+        let renderer = Renderer {
+            coverage: None,
+            ..*self.options
+        };
+        items
+            .into_iter()
+            .map(|(key, items)| {
+                let mut items: Vec<_> = items
+                    .into_iter()
+                    .map(|item| (renderer.try_render(&item).unwrap_or_default(), item))
+                    .collect();
+                items.sort_by(|a, b| a.0.cmp(&b.0));
+                items.dedup_by(|a, b| a.0 == b.0);
+                (key, Block::join(items.into_iter().map(|(_, item)| item)))
+            })
+            .collect()
+    }
+
+    fn resolve(&self, name: &str) -> Option<Block> {
+        if self.resolving.iter().any(|n| n == name) {
+            return None;
+        }
+        self.options.resolver.resolve(name)
+    }
+
+    /// The value of the placeholder of a condition from the resolver, escaped like a
+    /// value inserted with `Block::set` into a block with `escaper`.
+    fn resolve_condition(&self, name: &str, escaper: &Option<Arc<dyn Escaper>>) -> Option<Block> {
+        let value = self.resolve(name)?;
+        Some(Placeholder::new(name).fill_escaped(&value, escaper.as_deref()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        ident::Symbol, Block, File, Group, Indent, Line, LineSegment, RenderError, Renderer, Scope,
+        Slot,
+    };
+
+    fn two_lines() -> Block {
+        Block::join(vec![Block::from("a,"), Block::from("b")])
+    }
+
+    fn inline(before: &str, block: Block) -> Block {
+        Block::new(vec![Line(vec![
+            LineSegment::from(before),
+            LineSegment::Block(block),
+        ])])
+    }
+
+    #[test]
+    fn aligns_to_display_width_of_wide_characters() {
+        let block = inline("let 表 = [", two_lines());
+        assert_eq!(block.to_string(), "let 表 = [a,\n          b");
+
+        let block = inline("🙂(", two_lines());
+        assert_eq!(block.to_string(), "🙂(a,\n   b");
+    }
+
+    #[test]
+    fn ignores_combining_marks_when_aligning() {
+        let block = inline("cafe\u{301}(", two_lines());
+        assert_eq!(block.to_string(), "cafe\u{301}(a,\n     b");
+    }
+
+    #[test]
+    fn preserves_tabs_when_aligning() {
+        let block = inline("\tf(", two_lines());
+        assert_eq!(block.to_string(), "\tf(a,\n\t  b");
+    }
+
+    #[test]
+    fn expands_tabs_when_given_a_tab_width() {
+        let block = inline("\tf(", two_lines());
+        let output = Renderer::new().tab_width(4).render(&block);
+        assert_eq!(output, "\tf(a,\n      b");
+
+        let block = inline("ab\tf(", two_lines());
+        let output = Renderer::new().tab_width(4).render(&block);
+        assert_eq!(output, "ab\tf(a,\n      b");
+    }
+
+    #[test]
+    fn aligns_after_previously_embedded_blocks() {
+        let block = Block::new(vec![Line(vec![
+            LineSegment::Block(Block::from("foo")),
+            LineSegment::from(" = ["),
+            LineSegment::Block(two_lines()),
+        ])]);
+        assert_eq!(block.to_string(), "foo = [a,\n       b");
+    }
+
+    #[test]
+    fn inherits_leading_indentation_only() {
+        let block = inline("    return f(", two_lines().with_indent(Indent::Leading));
+        assert_eq!(block.to_string(), "    return f(a,\n    b");
+    }
+
+    #[test]
+    fn indents_hanging_blocks_by_one_more_level() {
+        let block = inline("    return f(", two_lines().with_indent(Indent::Hanging));
+        assert_eq!(block.to_string(), "    return f(a,\n        b");
+
+        let output = Renderer::new().indent_unit("\t").render(&block);
+        assert_eq!(output, "    return f(a,\n    \tb");
+    }
+
+    #[test]
+    fn does_not_indent_unindented_blocks() {
+        let block = inline("    SELECT ", two_lines().with_indent(Indent::None));
+        assert_eq!(block.to_string(), "    SELECT a,\nb");
+    }
+
+    #[test]
+    fn uses_the_indent_mode_of_a_placeholder() {
+        let block = File::parse("main =\n    return ${expr};\n    ${sql:none}\n----")
+            .unwrap()
+            .template("main")
+            .set("sql", two_lines());
+        assert_eq!(block.to_string(), "return ${expr};\na,\nb");
+
+        let block = File::parse("main = return ${expr:hanging};")
+            .unwrap()
+            .template("main");
+        let scope = Scope::new().set("expr", two_lines());
+        assert_eq!(block.render_with(&scope), "return a,\n    b;");
+    }
+
+    #[test]
+    fn set_indented_overrides_the_indent_mode_of_a_placeholder() {
+        let block = File::parse("main = return ${expr:hanging};")
+            .unwrap()
+            .template("main")
+            .set_indented("expr", two_lines(), Indent::Column);
+        assert_eq!(block.to_string(), "return a,\n       b;");
+    }
+
+    #[test]
+    fn end_of_input_ends_the_line() {
+        let block = Block::new(vec![Line(vec![
+            LineSegment::from("a"),
+            LineSegment::EndOfInput,
+        ])]);
+        assert_eq!(block.to_string(), "a\n");
+    }
+
+    #[test]
+    fn joins_terminated_blocks_without_blank_lines() {
+        let block = Block::join(vec![Block::from("a\n"), Block::from("b\n")]);
+        assert_eq!(block.to_string(), "a\nb\n");
+
+        let block = Block::join(vec![Block::from("a\n\n"), Block::from("b")]);
+        assert_eq!(block.to_string(), "a\n\nb");
+    }
+
+    #[test]
+    fn continues_after_a_terminated_block_at_the_outer_indentation() {
+        let block = Block::new(vec![
+            Line(vec![
+                LineSegment::from("  f("),
+                LineSegment::Block(Block::from("a,\nb\n")),
+                LineSegment::from(");"),
+            ]),
+            Line::from("  g();"),
+        ]);
+        assert_eq!(block.to_string(), "  f(a,\n    b\n  );\n  g();");
+    }
+
+    #[test]
+    fn does_not_indent_blank_lines() {
+        let block = inline("  f(", Block::from("a,\n\nb"));
+        assert_eq!(block.to_string(), "  f(a,\n\n    b");
+    }
+
+    #[test]
+    fn templates_can_end_with_a_newline() {
+        let f = File::parse("item =\n    x\n----+\n\nlast =\n    y\n----\n").unwrap();
+        let block = Block::join(vec![f.template("item"), f.template("item")]);
+        assert_eq!(block.to_string(), "x\nx\n");
+
+        let block = Block::join(vec![f.template("item"), f.template("last")]);
+        assert_eq!(block.to_string(), "x\ny");
+    }
+
+    const TYPE_TEMPLATES: &str = "type =
+    struct ${name} {
+        ${fields}
+        ${methods}
+    }
+----
+
+field = ${name}: ${type},
+";
+
+    #[test]
+    fn removes_lines_of_empty_placeholders() {
+        let f = File::parse(TYPE_TEMPLATES).unwrap();
+        let block = f
+            .template("type")
+            .set("name", "Empty")
+            .set("fields", Block::join(vec![]))
+            .set("methods", Block::empty());
+        let renderer = Renderer::new().remove_empty_placeholder_lines(true);
+
+        assert_eq!(block.to_string(), "struct Empty {\n\n\n}");
+        assert_eq!(renderer.render(&block), "struct Empty {\n}");
+
+        let block = f
+            .template("type")
+            .set("name", "One")
+            .set(
+                "fields",
+                Block::join(vec![
+                    f.template("field").set("name", "a").set("type", "u8"),
+                    Block::empty(),
+                ]),
+            )
+            .set("methods", "");
+        assert_eq!(renderer.render(&block), "struct One {\n    a: u8,\n}");
+    }
+
+    #[test]
+    fn keeps_lines_of_placeholders_that_render_something() {
+        let f = File::parse(TYPE_TEMPLATES).unwrap();
+        let scope = Scope::new()
+            .set("fields", Block::new(vec![Line(vec![]), Line(vec![])]))
+            .set("methods", Block::from("fn ${missing}() {}"));
+        let renderer = Renderer::new()
+            .remove_empty_placeholder_lines(true)
+            .resolver(&scope);
+
+        assert_eq!(
+            renderer.render(&f.template("type")),
+            "struct ${name} {\n\n\n    fn ${missing}() {}\n}"
+        );
+    }
+
+    #[test]
+    fn removes_lines_of_recursive_empty_placeholders() {
+        let block = File::parse("main =\n    a\n    ${x}\n    b\n----")
+            .unwrap()
+            .template("main");
+        let scope = Scope::new().set("x", Block::from("${x}${x}"));
+        let output = Renderer::new()
+            .remove_empty_placeholder_lines(true)
+            .resolver(&scope)
+            .render(&block);
+
+        assert_eq!(output, "a\n${x}${x}\nb");
+    }
+
+    #[test]
+    fn collapses_blank_lines() {
+        let block = Block::from("\n\na\n\n\n\nb\n\nc\n\n\n");
+        let output = Renderer::new().max_blank_lines(1).render(&block);
+        assert_eq!(output, "\na\n\nb\n\nc\n\n");
+
+        let output = Renderer::new().max_blank_lines(0).render(&block);
+        assert_eq!(output, "a\nb\nc\n");
+    }
+
+    #[test]
+    fn collapses_lines_of_indentation_as_blank_lines() {
+        let block = File::parse(TYPE_TEMPLATES)
+            .unwrap()
+            .template("type")
+            .set("name", "E")
+            .set("fields", Block::empty())
+            .set("methods", "");
+
+        assert_eq!(
+            Renderer::new().max_blank_lines(0).render(&block),
+            "struct E {\n}"
+        );
+        assert_eq!(
+            Renderer::new().max_blank_lines(1).render(&block),
+            "struct E {\n\n}"
+        );
+    }
+
+    #[test]
+    fn renders_deeply_nested_blocks() {
+        let mut block = Block::join(vec![Block::from("a"), Block::from("b")]);
+        for _ in 0..100_000 {
+            block = Block::new(vec![Line(vec![LineSegment::Block(block)])]);
+        }
+        let block = Block::join(vec![Block::from("start"), block, Block::from("end")]);
+
+        assert_eq!(block.to_string(), "start\na\nb\nend");
+    }
+
+    #[test]
+    fn compares_and_hashes_deeply_nested_blocks() {
+        let nested = |last: &str, scope: bool| {
+            let mut block = Block::from("a");
+            for _ in 0..100_000 {
+                block = Block::join(vec![block]);
+                if scope {
+                    block = block.symbol_scope();
+                }
+            }
+            Block::join(vec![block, Block::from(last)])
+        };
+        let (a, b, c) = (nested("b", false), nested("b", false), nested("c", false));
+        assert!(a == b && a != c);
+        let fingerprint = a.fingerprint();
+        assert!(b.fingerprint() == fingerprint && c.fingerprint() != fingerprint);
+        assert!(a == Block::join(vec![Block::from("a"), Block::from("b")]));
+
+        // Symbol scopes aren't flattened, so these stay nested when normalized
+        let (a, b, c) = (nested("b", true), nested("b", true), nested("c", true));
+        assert!(a == b && a != c);
+        let fingerprint = a.fingerprint();
+        assert!(b.fingerprint() == fingerprint && c.fingerprint() != fingerprint);
+    }
+
+    #[test]
+    fn renders_deeply_nested_joins() {
+        let mut block = Block::from("x");
+        for _ in 0..100_000 {
+            block = Block::join(vec![block]);
+        }
+        let block = Block::new(vec![Line(vec![
+            LineSegment::from("  - "),
+            LineSegment::Block(Block::join(vec![block, Block::from("y")])),
+        ])]);
+
+        assert_eq!(block.to_string(), "  - x\n    y");
+    }
+
+    const CONDITIONAL_TEMPLATES: &str = "struct =
+    ${?derive}#[derive(${derive})]
+    pub struct ${name} {
+        ${fields}
+    }
+    ${?methods}
+
+    impl ${name} {
+        ${methods}
+    }
+    ${/methods}
+----
+";
+
+    #[test]
+    fn keeps_conditional_lines_of_non_empty_placeholders() {
+        let f = File::parse(CONDITIONAL_TEMPLATES).unwrap();
+        let block = f
+            .template("struct")
+            .set("derive", Some("Debug"))
+            .set("name", "A")
+            .set("fields", "x: u8,")
+            .set("methods", "fn new() {}");
+
+        assert_eq!(
+            block.to_string(),
+            "#[derive(Debug)]\npub struct A {\n    x: u8,\n}\n\nimpl A {\n    fn new() {}\n}"
+        );
+    }
+
+    #[test]
+    fn removes_conditional_lines_of_empty_placeholders() {
+        let f = File::parse(CONDITIONAL_TEMPLATES).unwrap();
+        let block = f
+            .template("struct")
+            .set("derive", None::<&str>)
+            .set("name", "A")
+            .set("fields", "x: u8,")
+            .set("methods", Block::join(vec![Block::from("")]));
+
+        assert_eq!(block.to_string(), "pub struct A {\n    x: u8,\n}");
+    }
+
+    #[test]
+    fn resolves_conditions_at_render_time() {
+        let block = File::parse(CONDITIONAL_TEMPLATES)
+            .unwrap()
+            .template("struct")
+            .set("name", "A")
+            .set("fields", "");
+        let scope = Scope::new().set("derive", "Clone");
+
+        assert_eq!(
+            block.render_with(&scope),
+            "#[derive(Clone)]\npub struct A {\n\n}"
+        );
+        assert_eq!(block.to_string(), "pub struct A {\n\n}");
+    }
+
+    #[test]
+    fn prefixes_every_line_including_nested_blocks() {
+        let block = Block::join(vec![
+            Block::from("fn f() {"),
+            inline("    x(", two_lines()).terminated(),
+            Block::from(""),
+            Block::from("}"),
+        ])
+        .prefix_lines("// ");
+
+        assert_eq!(
+            block.to_string(),
+            "// fn f() {\n//     x(a,\n//       b\n//\n// }"
+        );
+    }
+
+    #[test]
+    fn prefixes_blocks_embedded_part_way_through_a_line() {
+        let commented = inline("f(", two_lines()).prefix_lines("# ");
+        let block = inline("x = ", commented);
+
+        assert_eq!(block.to_string(), "x = # f(a,\n    #   b");
+    }
+
+    #[test]
+    fn restores_the_line_prefix_after_prefixed_blocks_that_start_lines() {
+        let ended = Block::from(Line(vec![LineSegment::EndOfInput, LineSegment::from("(")]));
+        let commented = Block::join(vec![inline("", ended.prefix_lines("# ")), Block::empty()])
+            .with_indent(Indent::None)
+            .prefix_lines("# ");
+        let block = Block::from(Line(vec![
+            LineSegment::from("x = "),
+            LineSegment::Block(commented),
+            LineSegment::from(")"),
+        ]));
+
+        assert_eq!(block.to_string(), "x = # #\n# # (\n# )");
+    }
+
+    #[test]
+    fn indents_without_trailing_whitespace() {
+        let block = Block::join(vec![Block::from("a"), Block::from(""), two_lines()]).indent(2);
+
+        assert_eq!(block.to_string(), "  a\n\n  a,\n  b");
+        assert_eq!(block.indent(2).to_string(), "    a\n\n    a,\n    b");
+    }
+
+    fn call(name: &str, args: &[&str]) -> Block {
+        let args = args.iter().map(|&arg| Block::from(arg));
+        inline(name, Block::from(Group::new("(", args, ",", ")")))
+    }
+
+    #[test]
+    fn writes_groups_on_one_line_when_they_fit() {
+        let block = inline("    let x = ", call("f", &["alpha", "beta"]));
+        assert_eq!(
+            Renderer::new().width(26).render(&block),
+            "    let x = f(alpha, beta)"
+        );
+        assert_eq!(block.to_string(), "    let x = f(alpha, beta)");
+    }
+
+    #[test]
+    fn breaks_groups_that_do_not_fit() {
+        let block = Block::new(vec![Line(vec![
+            LineSegment::from("    let x = "),
+            LineSegment::Block(call("f", &["alpha", "beta"])),
+            LineSegment::from(";"),
+        ])]);
+        assert_eq!(
+            Renderer::new().width(26).render(&block),
+            "    let x = f(\n        alpha,\n        beta\n    );"
+        );
+        assert_eq!(
+            Renderer::new().width(27).render(&block),
+            "    let x = f(alpha, beta);"
+        );
+    }
+
+    #[test]
+    fn breaks_outer_groups_before_inner_ones() {
+        let inner = Group::new("[", vec![Block::from("1"), Block::from("2")], ",", "]");
+        let outer = Group::new(
+            "vec![",
+            vec![Block::from(inner.clone()), Block::from(inner)],
+            ",",
+            "]",
+        )
+        .with_trailing_separator();
+        let block = Block::from(outer);
+
+        assert_eq!(
+            Renderer::new().width(19).render(&block),
+            "vec![\n    [1, 2],\n    [1, 2],\n]"
+        );
+        assert_eq!(
+            Renderer::new().width(10).indent_unit("  ").render(&block),
+            "vec![\n  [1, 2],\n  [1, 2],\n]"
+        );
+        assert_eq!(
+            Renderer::new().width(7).indent_unit("  ").render(&block),
+            "vec![\n  [\n    1,\n    2\n  ],\n  [\n    1,\n    2\n  ],\n]"
+        );
+    }
+
+    #[test]
+    fn breaks_groups_containing_multiple_lines() {
+        let block = Block::from(Group::new("(", vec![two_lines()], ",", ")"));
+        assert_eq!(
+            Renderer::new().width(80).render(&block),
+            "(\n    a,\n    b\n)"
+        );
+        assert_eq!(block.to_string(), "(a,\n b)");
+    }
+
+    #[test]
+    fn writes_empty_groups_on_one_line() {
+        let block = inline("f", Block::from(Group::new("(", vec![], ",", ")")));
+        assert_eq!(Renderer::new().width(1).render(&block), "f()");
+    }
+
+    #[test]
+    fn aligns_columns_of_joined_rows() {
+        let fields = Block::join(vec![
+            Block::row(vec!["id:", " u64,"]),
+            Block::row(vec!["name:", " String,"]),
+        ]);
+        let block = Block::join(vec![
+            Block::from("struct A {"),
+            inline("    ", fields),
+            Block::from("}"),
+        ]);
+
+        assert_eq!(
+            block.to_string(),
+            "struct A {\n    id:   u64,\n    name: String,\n}"
+        );
+    }
+
+    #[test]
+    fn aligns_each_column_after_the_widest_cell_before_it() {
+        let block = Block::join(vec![
+            Block::row(vec!["A", "= 1,", "// first"]),
+            Block::row(vec!["Bee", "= 200,", "// second"]),
+            Block::row(vec!["C", "= 3,"]),
+            Block::from(""),
+            Block::row(vec!["Dee", "= 4,"]),
+        ]);
+
+        assert_eq!(
+            block.to_string(),
+            "A  = 1,  // first\nBee= 200,// second\nC  = 3,\n\nDee= 4,"
+        );
+    }
+
+    #[test]
+    fn aligns_template_lines_with_markers() {
+        let file = File::parse(
+            "arm =\n  ${pattern}${|} => ${value:leading},\n--\nmatch =\n  match x {\n      ${arms}\n  }\n--\n",
+        )
+        .unwrap();
+        let arms = Block::join(vec![
+            file.template("arm")
+                .set("pattern", "None")
+                .set("value", "0"),
+            file.template("arm")
+                .set("pattern", "Some(x)")
+                .set("value", "Block::join(vec![\n    x,\n])"),
+            file.template("arm").set("pattern", "_").set("value", "1"),
+        ]);
+        let block = file.template("match").set("arms", arms);
+
+        assert_eq!(
+            block.to_string(),
+            "match x {\n    None    => 0,\n    Some(x) => Block::join(vec![\n        x,\n    ]),\n    _       => 1,\n}"
+        );
+    }
+
+    #[test]
+    fn does_not_pad_lone_markers() {
+        let block = Block::join(vec![Block::from("a"), Block::row(vec!["b", "c"])]);
+        assert_eq!(block.to_string(), "a\nbc");
+    }
+
+    #[test]
+    fn wraps_text_at_the_width() {
+        let text = "The quick brown fox jumps over the lazy dog.\n\n  A second   paragraph,\nwith a line break. ";
+        let block = Block::wrapped(text, 20, "/// ");
+        assert_eq!(
+            block.to_string(),
+            "/// The quick brown\n/// fox jumps over\n/// the lazy dog.\n///\n/// A second\n/// paragraph, with\n/// a line break."
+        );
+    }
+
+    #[test]
+    fn wraps_text_from_the_column_it_is_inserted_at() {
+        let file =
+            File::parse("doc =\n  struct A {\n      ${doc}\n      a: u8,\n  }\n--\n").unwrap();
+        let doc = Block::wrapped("The quick brown fox jumps over the lazy dog.", 24, "/// ");
+        let block = file.template("doc").set("doc", doc);
+
+        assert_eq!(
+            block.to_string(),
+            "struct A {\n    /// The quick brown\n    /// fox jumps over\n    /// the lazy dog.\n    a: u8,\n}"
+        );
+    }
+
+    #[test]
+    fn puts_long_words_on_their_own_line() {
+        let block = Block::wrapped("see https://example.com/a/long/url for details", 16, " * ");
+        assert_eq!(
+            block.to_string(),
+            " * see\n * https://example.com/a/long/url\n * for details"
+        );
+        assert!(Block::wrapped(" \n ", 16, " * ").is_empty());
+    }
+
+    #[test]
+    fn fills_hoisted_items_from_the_whole_tree() {
+        let file = File::parse(
+            "module =\n  ${imports}\n\n  ${body}\n--\nfunction = function ${name}() {}\n",
+        )
+        .unwrap();
+        let function = |name: &str, import: &str| {
+            file.template("function").set("name", name).hoist(
+                "imports",
+                format!("import {{ {} }} from './{}';", import, import),
+            )
+        };
+        let body = Block::join(vec![
+            function("b", "zip"),
+            Block::new(vec![Line(vec![LineSegment::Placeholder("later".into())])]),
+            function("a", "map"),
+            function("c", "zip"),
+        ]);
+        let block = file
+            .template("module")
+            .set("imports", Block::hoisted("imports"))
+            .set("body", body);
+
+        let scope = Scope::new().set("later", function("d", "fold"));
+        let output = Renderer::new().resolver(&scope).render(&block);
+
+        assert_eq!(
+            output,
+            "import { fold } from './fold';\nimport { map } from './map';\nimport { zip } from './zip';\n\nfunction b() {}\nfunction d() {}\nfunction a() {}\nfunction c() {}"
+        );
+    }
+
+    #[test]
+    fn removes_lines_of_empty_hoisted_items() {
+        let block = Block::join(vec![Block::hoisted("imports"), Block::from("code")]);
+        assert_eq!(block.to_string(), "\ncode");
+
+        let output = Renderer::new()
+            .remove_empty_placeholder_lines(true)
+            .render(&block);
+        assert_eq!(output, "code");
+    }
+
+    #[test]
+    fn names_symbols_in_the_order_they_are_written() {
+        let file = File::parse("let = let ${x} = ${value};\n").unwrap();
+        let second = Symbol::new("x");
+        let first = Symbol::new("x");
+        let block = Block::join(vec![
+            file.template("let").set("x", &first).set("value", "0"),
+            file.template("let").set("x", &second).set("value", &first),
+        ]);
+
+        let output = "let x_1 = 0;\nlet x_2 = x_1;";
+        assert_eq!(block.to_string(), output);
+        assert_eq!(Renderer::new().render(&block), output);
+    }
+
+    #[test]
+    fn names_symbols_separately_in_sibling_scopes() {
+        let file = File::parse(
+            "static = static ${global}: u8 = 0;\nfunction =\n  fn ${name}() {\n      let ${tmp} = 1;\n      let ${helper} = ${tmp};\n      ${global}\n  }\n--\n",
+        )
+        .unwrap();
+        let global = Symbol::new("tmp");
+        let function = |name: &str| {
+            file.template("function")
+                .set("name", name)
+                .set("tmp", Symbol::new("tmp"))
+                .set("helper", Symbol::new("tmp"))
+                .set("global", &global)
+                .symbol_scope()
+        };
+        let block = Block::join(vec![
+            file.template("static").set("global", &global),
+            function("a"),
+            function("b"),
+        ]);
+
+        assert_eq!(
+            block.to_string(),
+            "static tmp_1: u8 = 0;\nfn a() {\n    let tmp_2 = 1;\n    let tmp_3 = tmp_2;\n    tmp_1\n}\nfn b() {\n    let tmp_2 = 1;\n    let tmp_3 = tmp_2;\n    tmp_1\n}"
+        );
+    }
+
+    #[test]
+    fn renders_slots_filled_after_they_were_inserted() {
+        let file = File::parse("header = // ${count} functions\n").unwrap();
+        let count = Slot::new("count");
+        let names = ["a", "b", "c"];
+        let mut functions = Vec::new();
+        let block = Block::join(vec![
+            file.template("header").set("count", &count),
+            Block::join_each(names.iter(), |name, _| {
+                functions.push(name);
+                Block::from(format!("fn {}() {{}}", name))
+            }),
+        ]);
+        count.fill(functions.len().to_string());
+
+        assert_eq!(
+            Renderer::new().try_render(&block),
+            Ok("// 3 functions\nfn a() {}\nfn b() {}\nfn c() {}".to_string())
+        );
+    }
+
+    #[test]
+    fn fails_to_render_unfilled_slots() {
+        let block = Block::join(vec![Block::from("a"), Block::from(Slot::new("total"))]);
+        assert_eq!(
+            Renderer::new().try_render(&block),
+            Err(RenderError::UnfilledSlot("total".into()))
+        );
+    }
+
+    #[test]
+    #[should_panic(expected = "Slot 'total' was never filled")]
+    fn panics_when_rendering_unfilled_slots() {
+        Renderer::new().render(&Block::from(Slot::new("total")));
+    }
+
+    #[test]
+    #[should_panic(expected = "Slot 'total' was never filled")]
+    fn panics_when_displaying_unfilled_slots() {
+        let _ = Block::join(vec![Block::from("a"), Block::from(Slot::new("total"))]).to_string();
+    }
+
+    #[test]
+    fn fails_to_render_slots_that_contain_themselves() {
+        let slot = Slot::new("body");
+        slot.fill(Block::join(vec![Block::from("a"), Block::from(&slot)]));
+        let block = Block::join(vec![Block::from(&slot), Block::from(&slot)]);
+        assert_eq!(
+            Renderer::new().try_render(&block),
+            Err(RenderError::RecursiveSlot("body".into()))
+        );
+
+        // Only the slot's own value counts, not other uses of it
+        let other = Slot::new("other");
+        other.fill("x");
+        let block = Block::join(vec![Block::from(&other), Block::from(&other)]);
+        assert_eq!(Renderer::new().try_render(&block), Ok("x\nx".into()));
+
+        // Checking whether lines are empty doesn't loop either
+        let empty = Slot::new("empty");
+        empty.fill(Block::from(&empty));
+        let renderer = Renderer::new().remove_empty_placeholder_lines(true);
+        assert_eq!(
+            renderer.try_render(&Block::from(&empty)),
+            Err(RenderError::RecursiveSlot("empty".into()))
+        );
+        assert_eq!(
+            format!("{:?}", empty),
+            "Slot { name: \"empty\", filled: true }"
+        );
+    }
+
+    #[test]
+    fn escapes_the_values_of_slots() {
+        let file = File::parse("@language json\nmain = \"${v}\"").unwrap();
+        let slot = Slot::new("v");
+        let block = file.template("main").set("v", &slot);
+        slot.fill("a\"b");
+
+        assert_eq!(block.to_string(), r#""a\"b""#);
+        assert_eq!(
+            Renderer::new().render_with_source_map(&Block::from(Slot::new("x"))),
+            Err(RenderError::UnfilledSlot("x".into()))
+        );
+    }
+
+    fn struct_file() -> File {
+        File::parse(
+            "struct =\n  struct ${name} {\n      ${fields}\n  }\n--\nfield = ${name}: ${type},\n",
+        )
+        .unwrap()
+        .with_path("types.jens")
+    }
+
+    fn struct_block(file: &File) -> Block {
+        let field = |name: &str, ty: &str| file.template("field").set("name", name).set("type", ty);
+        file.template("struct").set("name", "A").set(
+            "fields",
+            Block::join(vec![
+                field("x", "u8"),
+                Block::from("// text"),
+                field("y", "u8"),
+            ]),
+        )
+    }
+
+    #[test]
+    fn writes_sources_in_comments() {
+        let file = struct_file();
+        let output = Renderer::new()
+            .source_comments("/* ", " */")
+            .render(&struct_block(&file));
+
+        assert_eq!(
+            output,
+            "struct A { /* struct (types.jens:2) */\n    x: u8, /* field (types.jens:6) */\n    // text /* struct (types.jens:3) */\n    y: u8, /* field (types.jens:6) */\n} /* struct (types.jens:4) */"
+        );
+    }
+
+    #[test]
+    fn maps_output_lines_to_sources() {
+        let file = struct_file();
+        let block = Block::join(vec![struct_block(&file), Block::from("")]);
+        let (output, source_map) = Renderer::new().render_with_source_map(&block).unwrap();
+
+        assert_eq!(output, block.to_string());
+        let lines: Vec<_> = source_map
+            .lines
+            .iter()
+            .map(|source| source.as_ref().map(|source| source.line))
+            .collect();
+        assert_eq!(
+            lines,
+            vec![Some(2), Some(6), Some(3), Some(6), Some(4), None]
+        );
+        assert_eq!(
+            source_map.to_json().lines().nth(2).unwrap(),
+            "  {\"line\": 2, \"template\": \"field\", \"file\": \"types.jens\", \"source_line\": 6},"
+        );
+    }
+}
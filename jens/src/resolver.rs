@@ -0,0 +1,193 @@
+use crate::block::Block;
+use std::{collections::HashMap, hash::BuildHasher};
+
+/// Provides values for placeholders at render time, instead of eagerly calling
+/// `Block::set` for each one.
+pub trait Resolver {
+    /// Look up the value for a placeholder. Returning `None` leaves the placeholder
+    /// for an outer scope (or unset, if there is none).
+    fn resolve(&self, name: &str) -> Option<Block>;
+}
+
+/// The empty context, which resolves nothing.
+impl Resolver for () {
+    fn resolve(&self, _name: &str) -> Option<Block> {
+        None
+    }
+}
+
+impl<S: BuildHasher> Resolver for HashMap<String, Block, S> {
+    fn resolve(&self, name: &str) -> Option<Block> {
+        self.get(name).cloned()
+    }
+}
+
+impl<F> Resolver for F
+where
+    F: Fn(&str) -> Option<Block>,
+{
+    fn resolve(&self, name: &str) -> Option<Block> {
+        self(name)
+    }
+}
+
+/// Two resolvers combined, where `inner` takes precedence over `outer`.
+pub struct Chain<'a> {
+    inner: &'a dyn Resolver,
+    outer: &'a dyn Resolver,
+}
+
+impl<'a> Chain<'a> {
+    pub fn new(inner: &'a dyn Resolver, outer: &'a dyn Resolver) -> Self {
+        Chain { inner, outer }
+    }
+}
+
+impl<'a> Resolver for Chain<'a> {
+    fn resolve(&self, name: &str) -> Option<Block> {
+        self.inner
+            .resolve(name)
+            .or_else(|| self.outer.resolve(name))
+    }
+}
+
+/// A set of placeholder values that can be nested inside a parent context.
+///
+/// Values set on a child scope shadow those of the same name in its parents, while
+/// anything the child doesn't know about is looked up in the parent.
+#[derive(Default)]
+pub struct Scope<'a> {
+    values: HashMap<String, Block>,
+    parent: Option<&'a dyn Resolver>,
+}
+
+impl<'a> Scope<'a> {
+    /// Create a new top-level scope.
+    pub fn new() -> Self {
+        Scope::default()
+    }
+
+    /// Create a new scope which falls back to `parent` for unknown placeholders.
+    pub fn with_parent(parent: &'a dyn Resolver) -> Self {
+        Scope {
+            values: HashMap::new(),
+            parent: Some(parent),
+        }
+    }
+
+    /// Create a new scope nested inside this one.
+    pub fn child(&self) -> Scope<'_> {
+        Scope::with_parent(self)
+    }
+
+    /// Set the value of a placeholder in this scope.
+    pub fn set<T: Into<Block>>(mut self, placeholder_name: &str, content: T) -> Self {
+        self.insert(placeholder_name, content);
+        self
+    }
+
+    /// Set the value of a placeholder in this scope, without consuming the scope.
+    pub fn insert<T: Into<Block>>(&mut self, placeholder_name: &str, content: T) {
+        self.values.insert(placeholder_name.into(), content.into());
+    }
+}
+
+impl<'a> Resolver for Scope<'a> {
+    fn resolve(&self, name: &str) -> Option<Block> {
+        match self.values.get(name) {
+            Some(value) => Some(value.clone()),
+            None => self.parent.and_then(|parent| parent.resolve(name)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::File;
+
+    const TEMPLATES: &str = "main =
+    struct ${name} {
+        ${fields}
+    }
+----
+
+field = ${name}: ${type},
+";
+
+    #[test]
+    fn resolves_placeholders_from_a_map() {
+        let mut values = HashMap::new();
+        values.insert(String::from("x"), Block::from("B"));
+
+        let block = File::parse("main = A${x}C${y}").unwrap().template("main");
+
+        assert_eq!(block.render_with(&values), "ABC${y}");
+    }
+
+    #[test]
+    fn resolves_placeholders_from_a_closure() {
+        let block = File::parse("main = ${a} ${b}").unwrap().template("main");
+        let resolver = |name: &str| Some(Block::from(name.to_uppercase()));
+
+        assert_eq!(block.render_with(&resolver), "A B");
+    }
+
+    #[test]
+    fn resolves_placeholders_inside_nested_blocks() {
+        let f = File::parse(TEMPLATES).unwrap();
+        let block = f.template("main").set(
+            "fields",
+            Block::join(vec![
+                f.template("field").set("name", "a"),
+                f.template("field").set("name", "b"),
+            ]),
+        );
+        let scope = Scope::new().set("name", "Test").set("type", "u32");
+
+        assert_eq!(
+            block.render_with(&scope),
+            "struct Test {\n    a: u32,\n    b: u32,\n}"
+        );
+    }
+
+    #[test]
+    fn inner_scopes_shadow_outer_scopes() {
+        let block = File::parse("main = ${a} ${b}").unwrap().template("main");
+        let outer = Scope::new().set("a", "outer a").set("b", "outer b");
+        let inner = outer.child().set("a", "inner a");
+
+        assert_eq!(block.render_with(&inner), "inner a outer b");
+        assert_eq!(block.render_with(&outer), "outer a outer b");
+    }
+
+    #[test]
+    fn chains_resolvers() {
+        let block = File::parse("main = ${a} ${b}").unwrap().template("main");
+        let fallback = |_: &str| Some(Block::from("?"));
+        let scope = Scope::new().set("a", "A");
+
+        assert_eq!(block.render_with(&Chain::new(&scope, &fallback)), "A ?");
+    }
+
+    #[test]
+    fn does_not_resolve_recursive_values_forever() {
+        let block = File::parse("main = <${a}>").unwrap().template("main");
+        let scope = Scope::new().set("a", block.clone());
+
+        assert_eq!(block.render_with(&scope), "<<${a}>>");
+    }
+
+    #[test]
+    fn resolved_blocks_are_indented() {
+        let block = File::parse("main = x = ${value};")
+            .unwrap()
+            .template("main");
+        let scope = Scope::new().set(
+            "value",
+            Block::join(vec![Block::from("1 +"), Block::from("2")]),
+        );
+
+        assert_eq!(block.render_with(&scope), "x = 1 +\n    2;");
+    }
+}
@@ -2,7 +2,7 @@
 extern crate jens;
 
 use jens::File as JensFile;
-use jens::{Block, LineSegment};
+use jens::{Block, Indent, LineSegment};
 use quote::quote;
 use std::env;
 use std::fs::File;
@@ -20,7 +20,7 @@ fn load_file<P: AsRef<Path>>(path: P) -> io::Result<String> {
 
 fn get_path(path: &str) -> PathBuf {
     let root = env::var("CARGO_MANIFEST_DIR").unwrap_or_else(|_| ".".into());
-    let path = Path::new(&root).join("src/").join(&path);
+    let path = Path::new(&root).join("src/").join(path);
     // let file_name = match path.file_name() {
     //         Some(file_name) => file_name,
     //         None => panic!("template attribute should point to a file"),
@@ -28,6 +28,16 @@ fn get_path(path: &str) -> PathBuf {
     path
 }
 
+fn indent_variant(indent: Indent) -> Ident {
+    let variant = match indent {
+        Indent::Column => "Column",
+        Indent::Leading => "Leading",
+        Indent::Hanging => "Hanging",
+        Indent::None => "None",
+    };
+    Ident::new(variant, Span::call_site())
+}
+
 #[proc_macro_derive(Template, attributes(filename))]
 pub fn derive_jens(input: TokenStream) -> TokenStream {
     let input = parse_macro_input!(input as DeriveInput);
@@ -35,15 +45,12 @@ pub fn derive_jens(input: TokenStream) -> TokenStream {
     let mut filename = None;
     // Parse out the #[filename = "filename"] attribute from the derive
     for attr in input.attrs {
-        match attr.parse_meta().unwrap() {
-            syn::Meta::NameValue(v) => {
-                if v.ident.to_string() == "filename" {
-                    if let syn::Lit::Str(s) = v.lit {
-                        filename = Some(s.value());
-                    }
+        if let syn::Meta::NameValue(v) = attr.parse_meta().unwrap() {
+            if v.ident == "filename" {
+                if let syn::Lit::Str(s) = v.lit {
+                    filename = Some(s.value());
                 }
             }
-            _ => {}
         }
     }
 
@@ -55,12 +62,28 @@ pub fn derive_jens(input: TokenStream) -> TokenStream {
         Ok(data) => data,
         Err(error) => panic!("error opening {:?}: {}", &filename, error),
     };
-    let file = JensFile::parse(&data).unwrap();
+    let file = match JensFile::parse(&data) {
+        Ok(file) => file.with_path(&filename),
+        Err(()) => panic!("error parsing {:?}", &filename),
+    };
 
     let mut template_funcs = Vec::new();
     for t in file.templates.iter() {
         let func_ident = Ident::new(&t.name, Span::call_site());
 
+        // Text values are escaped for the template's language, as `Block::set` would,
+        // after converting their case
+        let language = t
+            .language
+            .map(|language| Ident::new(&format!("{:?}", language), Span::call_site()));
+        let escaper = match &language {
+            Some(language) => quote! { Some(&jens::Language::#language) },
+            None => quote! { None },
+        };
+        let with_escaper = language
+            .as_ref()
+            .map(|language| quote! { .with_escaper(jens::Language::#language) });
+
         let placeholder_names = t.placeholder_names();
         let args = placeholder_names.iter().map(|p| {
             let placeholder_ident = Ident::new(&format!("placeholder_{}", p), Span::call_site());
@@ -68,15 +91,16 @@ pub fn derive_jens(input: TokenStream) -> TokenStream {
         });
         let blocks = placeholder_names.iter().map(|p| {
             let placeholder_ident = Ident::new(&format!("placeholder_{}", p), Span::call_site());
+            let raw_ident = Ident::new(&format!("raw_{}", p), Span::call_site());
             let block_ident = Ident::new(&format!("block_{}", p), Span::call_site());
             quote! {
-                    let #block_ident: Block = #placeholder_ident.into();
+                    let #raw_ident: Block = #placeholder_ident.into();
+                    let #block_ident: Block = jens::Placeholder::new(#p).fill_escaped(&#raw_ident, #escaper);
             }
         });
         let block: Block = t.into();
         let lines: Vec<_> = block
-            .0
-            .iter()
+            .lines()
             .map(|line| {
                 let segments: Vec<_> = line
                     .0
@@ -85,14 +109,62 @@ pub fn derive_jens(input: TokenStream) -> TokenStream {
                         LineSegment::EndOfInput => {
                             quote! {jens::LineSegment::EndOfInput}
                         }
+                        LineSegment::Align => quote! {jens::LineSegment::Align},
+                        LineSegment::Source(s) => {
+                            let template: &str = &s.template;
+                            let file = match s.file.as_deref() {
+                                Some(file) => quote! { Some(std::borrow::Cow::Borrowed(#file)) },
+                                None => quote! { None },
+                            };
+                            let line = s.line;
+                            quote! {
+                                jens::LineSegment::Source(std::sync::Arc::new(jens::Source {
+                                    template: std::borrow::Cow::Borrowed(#template),
+                                    file: #file,
+                                    line: #line,
+                                }))
+                            }
+                        }
                         LineSegment::Content(c) => {
-                            quote! {jens::LineSegment::Content(#c.into())}
+                            let c: &str = c;
+                            quote! {jens::LineSegment::Content(std::borrow::Cow::Borrowed(#c))}
+                        }
+                        LineSegment::Placeholder(p) => {
+                            let ident = Ident::new(&format!("block_{}", p.name), Span::call_site());
+                            if p.indent.is_none() && p.case.is_none() {
+                                return quote! {jens::LineSegment::Block(#ident.clone())};
+                            }
+                            let ident = Ident::new(&format!("raw_{}", p.name), Span::call_site());
+                            let name = &p.name;
+                            let indent = p.indent.map(indent_variant).map(|indent| {
+                                quote! { .with_indent(jens::Indent::#indent) }
+                            });
+                            let case = p.case.map(|case| {
+                                let case = Ident::new(&format!("{:?}", case), Span::call_site());
+                                quote! { .with_case(jens::ident::Case::#case) }
+                            });
+                            quote! {
+                                jens::LineSegment::Block(
+                                    jens::Placeholder::new(#name)#indent#case.fill_escaped(&#ident, #escaper)
+                                )
+                            }
                         }
-                        LineSegment::Placeholder(c) => {
-                            let ident = Ident::new(&format!("block_{}", c), Span::call_site());
-                            quote! {jens::LineSegment::Block(#ident.clone())}
+                        LineSegment::Condition(c) => {
+                            let name = &c.name;
+                            let ident = Ident::new(&format!("block_{}", name), Span::call_site());
+                            quote! {
+                                jens::LineSegment::Condition(jens::Condition {
+                                    name: #name.into(),
+                                    value: Some(#ident.clone()),
+                                })
+                            }
                         }
-                        LineSegment::Block(_) => {
+                        LineSegment::Block(_)
+                        | LineSegment::Group(_)
+                        | LineSegment::Wrapped(_)
+                        | LineSegment::Hoisted(_)
+                        | LineSegment::Symbol(_)
+                        | LineSegment::Slot(_) => {
                             panic!("Unexpected block inside template");
                         }
                     })
@@ -103,7 +175,7 @@ pub fn derive_jens(input: TokenStream) -> TokenStream {
         template_funcs.push(quote! {
                 pub fn #func_ident(#(#args),*) -> Block {
                         #(#blocks)*
-                        jens::Block(vec![#(#lines),*])
+                        jens::Block::new(vec![#(#lines),*])#with_escaper
                 }
         })
     }
@@ -125,7 +197,7 @@ mod test {
     struct SampleTemplate {}
     impl SampleTemplate {
         fn template1() -> Block {
-            Block(vec![Line(vec![LineSegment::Content(
+            Block::new(vec![Line(vec![LineSegment::Content(
                 "Simple template".into(),
             )])])
         }
@@ -133,7 +205,7 @@ mod test {
         fn template2(placeholder1: impl Into<Block>, placeholder2: impl Into<Block>) -> Block {
             let b1: Block = placeholder1.into();
             let b2: Block = placeholder2.into();
-            Block(vec![Line(vec![
+            Block::new(vec![Line(vec![
                 LineSegment::Content("Template with [".into()),
                 LineSegment::Block(b1.clone()),
                 LineSegment::Content("] and [".into()),
@@ -2,6 +2,7 @@
 pub mod tests {
     use jens::Block;
     use jens_derive::Template;
+    #[allow(clippy::enum_variant_names)]
     pub enum Json {
         JsString,
         JsNumber,
@@ -37,8 +38,8 @@ pub mod tests {
         fn get_serialize_func(&self) -> Block {
             use Json::*;
             match self {
-                JsString => format!("noop").into(),
-                JsNumber => format!("noop").into(),
+                JsString => "noop".into(),
+                JsNumber => "noop".into(),
                 JsArray(subtype) => {
                     Template::fn_call("serialize_array", subtype.get_serialize_func())
                 }
@@ -49,8 +50,8 @@ pub mod tests {
         fn get_deserialize_func(&self) -> Block {
             use Json::*;
             match self {
-                JsString => format!("deserialize_string").into(),
-                JsNumber => format!("deserialize_number").into(),
+                JsString => "deserialize_string".into(),
+                JsNumber => "deserialize_number".into(),
                 JsArray(subtype) => {
                     Template::fn_call("deserialize_array", subtype.get_deserialize_func())
                 }
@@ -60,13 +61,13 @@ pub mod tests {
     }
 
     fn type_def_fields(t: &TsType) -> Block {
-        Block::join_map(&t.fields, |field, _| {
+        Block::join_each(&t.fields, |field, _| {
             Template::type_def(field.field_name.clone(), field.field_type.get_ts_type())
         })
     }
 
     fn serialize_fields(t: &TsType) -> Block {
-        Block::join_map(&t.fields, |field, _| {
+        Block::join_each(&t.fields, |field, _| {
             Template::serialize_field(
                 field.field_name.clone(),
                 field.field_type.get_serialize_func(),
@@ -75,7 +76,7 @@ pub mod tests {
     }
 
     fn deserialize_fields(t: &TsType) -> Block {
-        Block::join_map(&t.fields, |field, _| {
+        Block::join_each(&t.fields, |field, _| {
             Template::deserialize_field(
                 field.field_name.clone(),
                 field.field_type.get_deserialize_func(),
@@ -84,7 +85,7 @@ pub mod tests {
     }
 
     pub fn generate(types: Vec<TsType>) -> Block {
-        Template::main(Block::join_map(types, |t, _| {
+        Template::main(Block::join_each(types, |t, _| {
             Template::type_module(
                 t.type_name.clone(),
                 type_def_fields(&t),
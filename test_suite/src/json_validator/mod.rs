@@ -5,10 +5,9 @@ mod tests {
     use super::generator::tests::*;
     use insta::assert_snapshot_matches;
 
-    #[test]
-    fn test_derive_json_validator() {
-        // A sample Json object schema
-        let types = vec![
+    // A sample Json object schema
+    fn types() -> Vec<TsType> {
+        vec![
             TsType {
                 type_name: "Book".into(),
                 fields: vec![TsField {
@@ -46,8 +45,27 @@ mod tests {
                     },
                 ],
             },
-        ];
+        ]
+    }
+
+    #[test]
+    fn test_derive_json_validator() {
+        assert_snapshot_matches!("test_derive_json_validator", generate(types()).to_string());
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn test_serialized_output_round_trips() {
+        // Blocks can be cached between the steps of a build
+        let block = generate(types());
+        let json = serde_json::to_string(&block).unwrap();
+        let cached: jens::Block = serde_json::from_str(&json).unwrap();
 
-        assert_snapshot_matches!("test_derive_json_validator", generate(types).to_string());
+        assert_eq!(cached, block);
+        assert_eq!(cached.to_string(), block.to_string());
+        assert_eq!(
+            cached.lines().next().unwrap().source(),
+            block.lines().next().unwrap().source()
+        );
     }
 }
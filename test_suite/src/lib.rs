@@ -18,4 +18,57 @@ mod tests {
         assert_snapshot_matches!("test_derive_simple", t2.to_string());
     }
 
+    #[test]
+    fn test_derive_indent_mode() {
+        let expr = Block::join(vec![Block::from("a &&"), Block::from("b")]);
+        let t3 = Xyz::template3(expr);
+
+        assert_eq!(t3.to_string(), "return a &&\n    b;");
+    }
+
+    #[test]
+    fn test_derive_conditional_line() {
+        let t4 = Xyz::template4(Some("Debug"), "A");
+        assert_eq!(t4.to_string(), "#[derive(Debug)]\nstruct A;");
+
+        let t4 = Xyz::template4(None::<&str>, "A");
+        assert_eq!(t4.to_string(), "struct A;");
+    }
+
+    #[test]
+    fn test_derive_case_modifiers() {
+        let t6 = Xyz::template6("default value", "value_type");
+        assert_eq!(
+            t6.to_string(),
+            "const DEFAULT_VALUE: ValueType = ValueType::new();"
+        );
+    }
+
+    #[test]
+    fn test_derive_escaping() {
+        let t5 = Xyz::template5("say \"hi\"", Block::trusted("[1, 2]"));
+        assert_eq!(
+            t5.to_string(),
+            "{ \"name\": \"say \\\"hi\\\"\", \"value\": [1, 2] }"
+        );
+    }
+
+    #[test]
+    fn test_derive_case_modifiers_with_escaping() {
+        let t7 = Xyz::template7("FooBar", "say \"hi\"");
+        assert_eq!(t7.to_string(), "let foo_bar = \"say \\\"hi\\\"\";");
+    }
+
+    #[test]
+    fn test_derive_source_locations() {
+        let t2 = Xyz::template2(Xyz::template1(), "Hullo");
+        let source = t2.lines().nth(2).unwrap().source().unwrap();
+        assert_eq!(source.to_string(), "template2 (test.jens:8)");
+
+        let output = jens::Renderer::new().source_comments("# ", "").render(&t2);
+        assert_eq!(
+            output.lines().nth(1).unwrap(),
+            "  One: \"hello!\" # template1 (test.jens:2)"
+        );
+    }
 }